@@ -1,29 +1,265 @@
 /* SPDX-License-Identifier: MIT */
 
+use crate::message_id;
 use anyhow::{anyhow, Context, Result};
 use fasteval::Evaler;
 use ini::Ini;
 use liboverdrop::FragmentScanner;
-use log::{info, warn};
+use log::{debug, error, info, warn};
 use std::borrow::Cow;
-use std::collections::{BTreeMap, HashMap};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::fmt;
 use std::fs;
-use std::io::{prelude::*, BufReader};
+use std::io::{self, prelude::*, BufReader};
 use std::path::{Component, Path, PathBuf};
+use std::process::Command;
 
 const DEFAULT_ZRAM_SIZE: &str = "min(ram / 2, 4096)";
 
+/// A `[sysctl]` setting, applied while any swap device is configured
+#[derive(Clone, Debug, PartialEq)]
+pub struct Sysctl {
+    /// dot-separated, as in `vm.swappiness`
+    pub name: String,
+    pub value: String,
+    pub origin: Option<Origin>,
+}
+
+/// The configuration the generator acts on
+pub struct Config {
+    /// only those that will be created, sized
+    pub devices: Vec<Device>,
+    /// sorted by name
+    pub sysctl: Vec<Sysctl>,
+    /// Detected on first use, by `condition-virtualization` or the generator
+    pub virtualization: Virtualization,
+}
+
+/// A numeric sysfs attribute of the device, set from the key of the same name
+#[derive(Debug)]
+pub struct Tunable {
+    pub key: &'static str,
+    /// relative to /sys/block/zramN/
+    pub attribute: &'static str,
+    /// whether it must be set before disksize, or can only be set after
+    pub before_disksize: bool,
+    pub min: u64,
+}
+
+/// In the order they're applied, relative to disksize
+pub const TUNABLES: &[Tunable] = &[
+    Tunable {
+        key: "max-comp-streams",
+        attribute: "max_comp_streams",
+        before_disksize: true,
+        min: 1,
+    },
+    Tunable {
+        key: "read-ahead-kb",
+        attribute: "queue/read_ahead_kb",
+        before_disksize: false,
+        min: 0,
+    },
+    Tunable {
+        key: "nr-requests",
+        attribute: "queue/nr_requests",
+        before_disksize: false,
+        min: 4,
+    },
+    Tunable {
+        key: "max-sectors-kb",
+        attribute: "queue/max_sectors_kb",
+        before_disksize: false,
+        min: 4,
+    },
+];
+
+/// Location of a configuration key, or section header, in a fragment
+#[derive(Clone, Debug, PartialEq)]
+pub struct Origin {
+    pub path: PathBuf,
+    pub line: usize,
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.path.display(), self.line)
+    }
+}
+
+/// A compression algorithm, optionally with parameters, as in `zstd(priority=1)`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Algorithm {
+    pub name: String,
+    pub params: Vec<(String, String)>,
+}
+
+impl Algorithm {
+    pub fn param(&self, key: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .rev()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| &v[..])
+    }
+}
+
+impl fmt::Display for Algorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.name)?;
+        if !self.params.is_empty() {
+            f.write_str("(")?;
+            for (i, (k, v)) in self.params.iter().enumerate() {
+                if i != 0 {
+                    f.write_str(",")?;
+                }
+                write!(f, "{}={}", k, v)?;
+            }
+            f.write_str(")")?;
+        }
+        Ok(())
+    }
+}
+
+/// A `condition-*` value, negated with a leading "!"
+#[derive(Clone, Debug, PartialEq)]
+pub struct Negatable<T> {
+    pub negate: bool,
+    pub value: T,
+}
+
+impl<T: fmt::Display> fmt::Display for Negatable<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.negate {
+            f.write_str("!")?;
+        }
+        self.value.fmt(f)
+    }
+}
+
+/// How to follow changes of the total memory after the device was set up
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HotplugPolicy {
+    Ignore,
+    GrowOnly,
+    GrowAndShrink,
+}
+
+impl HotplugPolicy {
+    const ALL: [(&'static str, HotplugPolicy); 3] = [
+        ("ignore", HotplugPolicy::Ignore),
+        ("grow-only", HotplugPolicy::GrowOnly),
+        ("grow-and-shrink", HotplugPolicy::GrowAndShrink),
+    ];
+
+    fn parse(value: &str) -> Result<HotplugPolicy> {
+        HotplugPolicy::ALL
+            .iter()
+            .find(|(name, _)| *name == value)
+            .map(|&(_, policy)| policy)
+            .ok_or_else(|| anyhow!("\"{}\" not ignore, grow-only, or grow-and-shrink", value))
+    }
+}
+
+impl fmt::Display for HotplugPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (name, _) = HotplugPolicy::ALL
+            .iter()
+            .find(|(_, p)| p == self)
+            .expect("all policies listed");
+        f.write_str(name)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Comparison {
+    Less,
+    LessEqual,
+    Equal,
+    NotEqual,
+    GreaterEqual,
+    Greater,
+}
+
+impl Comparison {
+    const ALL: [(&'static str, Comparison); 6] = [
+        ("<=", Comparison::LessEqual),
+        (">=", Comparison::GreaterEqual),
+        ("!=", Comparison::NotEqual),
+        ("<", Comparison::Less),
+        (">", Comparison::Greater),
+        ("=", Comparison::Equal),
+    ];
+
+    pub fn holds(self, lhs: u64, rhs: u64) -> bool {
+        match self {
+            Comparison::Less => lhs < rhs,
+            Comparison::LessEqual => lhs <= rhs,
+            Comparison::Equal => lhs == rhs,
+            Comparison::NotEqual => lhs != rhs,
+            Comparison::GreaterEqual => lhs >= rhs,
+            Comparison::Greater => lhs > rhs,
+        }
+    }
+}
+
+impl fmt::Display for Comparison {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (op, _) = Comparison::ALL
+            .iter()
+            .find(|(_, c)| c == self)
+            .expect("all comparisons listed");
+        f.write_str(op)
+    }
+}
+
 pub struct Device {
     pub name: String,
 
     pub host_memory_limit_mb: Option<u64>,
+    /// MB; the device isn't created on hosts with less memory
+    pub host_memory_minimum_mb: Option<u64>,
+    /// MB; enabled only if MemTotal compares true against this
+    pub condition_memory: Option<(Comparison, u64)>,
+    /// "yes", "no", "vm", "container", or a systemd-detect-virt(1) identifier
+    pub condition_virtualization: Option<Negatable<String>>,
+    /// words which must all be enabled (or not, if negated)
+    pub condition_kernel_command_line: Vec<Negatable<String>>,
+    /// as in systemd.unit(5) ConditionArchitecture=
+    pub condition_architecture: Option<Negatable<String>>,
+    /// relative to the root, like the configuration
+    pub condition_path_exists: Option<Negatable<PathBuf>>,
 
     /// Default: `DEFAULT_ZRAM_SIZE`
     pub zram_size: Option<(String, fasteval::ExpressionI, fasteval::Slab)>,
-    pub compression_algorithm: Option<String>,
+    /// Default: unlimited
+    pub mem_limit: Option<(String, fasteval::ExpressionI, fasteval::Slab)>,
+    /// tried in order until the kernel accepts one, with level and dict parameters, if any
+    pub compression_algorithms: Vec<Algorithm>,
+    /// secondary algorithms, with priorities 1, 2, … unless specified
+    pub recompression_algorithms: Vec<Algorithm>,
+    /// seconds; when set, a timer triggering `recompress` will be created
+    pub recompression_interval: Option<u64>,
+    /// "idle", "huge", or "huge_idle"
+    pub recompression_type: Cow<'static, str>,
+    /// bytes; only objects larger than this are recompressed
+    pub recompression_threshold: Option<u64>,
     pub writeback_dev: Option<PathBuf>,
+    /// seconds; when set, a timer triggering `writeback` will be created
+    pub writeback_interval: Option<u64>,
+    /// seconds; when set, only pages not accessed for this long are written back,
+    /// otherwise, pages not accessed since the previous writeback
+    pub writeback_idle_age: Option<u64>,
+    /// MB that may be written back in total, across all writeback runs, until the device is reset
+    pub writeback_limit_mb: Option<u64>,
     pub disksize: u64,
+    /// 0 if unlimited
+    pub mem_limit_bytes: u64,
+    /// when not Ignore, the device is resized when memory is hot-plugged
+    pub memory_hotplug: HotplugPolicy,
+    /// values of `TUNABLES`, by key
+    pub tunables: BTreeMap<&'static str, u64>,
 
     pub swap_priority: i32,
     /// when set, a mount unit will be created
@@ -32,29 +268,69 @@ pub struct Device {
     /// None is the same as "swap" when mount_point is not set
     pub fs_type: Option<String>,
     pub options: Cow<'static, str>,
+    /// permissions of the file system's root directory, set after creating it
+    pub root_mode: Option<u32>,
+    /// "user[:group]" owning the file system's root directory
+    pub root_owner: Option<String>,
+    /// SELinux label of the whole file system, as the context= mount option
+    pub selinux_context: Option<String>,
+    /// units the mount unit is ordered before
+    pub before: Vec<String>,
+    /// units whose .wants/ the mount unit is linked into; None is local-fs.target
+    pub wanted_by: Option<Vec<String>>,
 
     /// deprecated, overrides zram_size
     pub zram_fraction: Option<f64>,
     /// deprecated, overrides zram_size
     pub max_zram_size_mb: Option<Option<u64>>,
+
+    /// the header of the first section defining the device; None if only systemd.zram did
+    pub defined_at: Option<Origin>,
+    /// where each key was last set, by canonical key name
+    pub origins: BTreeMap<String, Origin>,
 }
 
 impl Device {
-    fn new(name: String) -> Device {
+    pub(crate) fn new(name: String) -> Device {
         Device {
             name,
             host_memory_limit_mb: None,
+            host_memory_minimum_mb: None,
+            condition_memory: None,
+            condition_virtualization: None,
+            condition_kernel_command_line: vec![],
+            condition_architecture: None,
+            condition_path_exists: None,
             zram_size: None,
-            compression_algorithm: None,
+            mem_limit: None,
+            compression_algorithms: vec![],
+            recompression_algorithms: vec![],
+            recompression_interval: None,
+            recompression_type: "idle".into(),
+            recompression_threshold: None,
             writeback_dev: None,
+            writeback_interval: None,
+            writeback_idle_age: None,
+            writeback_limit_mb: None,
             disksize: 0,
+            mem_limit_bytes: 0,
+            memory_hotplug: HotplugPolicy::Ignore,
+            tunables: BTreeMap::new(),
             swap_priority: 100,
             mount_point: None,
             fs_type: None,
             options: "discard".into(),
+            root_mode: None,
+            root_owner: None,
+            selinux_context: None,
+            before: vec![],
+            wanted_by: None,
 
             zram_fraction: None,
             max_zram_size_mb: None,
+
+            defined_at: None,
+            origins: BTreeMap::new(),
         }
     }
 
@@ -63,20 +339,103 @@ impl Device {
             && (self.fs_type.is_none() || self.fs_type.as_ref().unwrap() == "swap")
     }
 
-    fn is_enabled(&self, memtotal_mb: u64) -> bool {
-        match self.host_memory_limit_mb {
-            Some(limit_mb) if limit_mb < memtotal_mb => {
+    fn is_enabled(&self, host: &HostInfo) -> bool {
+        match self.disabled_reason(host) {
+            Some(reason) => {
                 info!(
-                    "{}: system has too much memory ({:.1}MB), limit is {}MB, ignoring.",
-                    self.name,
-                    memtotal_mb,
-                    self.host_memory_limit_mb.unwrap()
+                    zram_device = self.name.as_str(),
+                    message_id = message_id::DEVICE_DISABLED;
+                    "{}: {}, ignoring.", self.name, reason
                 );
-
                 false
             }
-            _ => true,
+            None => true,
+        }
+    }
+
+    fn disabled_reason(&self, host: &HostInfo) -> Option<String> {
+        let memtotal_mb = host.memtotal_mb;
+        match self.host_memory_limit_mb {
+            Some(limit_mb) if limit_mb < memtotal_mb => {
+                return Some(format!(
+                    "system has too much memory ({:.1}MB), limit is {}MB",
+                    memtotal_mb, limit_mb
+                ));
+            }
+            _ => {}
+        }
+        match self.host_memory_minimum_mb {
+            Some(minimum_mb) if minimum_mb > memtotal_mb => {
+                return Some(format!(
+                    "system has too little memory ({:.1}MB), minimum is {}MB",
+                    memtotal_mb, minimum_mb
+                ));
+            }
+            _ => {}
+        }
+
+        if let Some((cmp, mb)) = self.condition_memory {
+            if !cmp.holds(memtotal_mb, mb) {
+                return Some(format!(
+                    "system memory ({}MB) doesn't satisfy condition-memory={}{}",
+                    memtotal_mb, cmp, mb
+                ));
+            }
+        }
+
+        if let Some(ref cond) = self.condition_virtualization {
+            let virt = host.virtualization.get();
+            if virtualization_matches(virt.as_deref(), &cond.value) == cond.negate {
+                return Some(format!(
+                    "virtualization ({}) doesn't satisfy condition-virtualization={}",
+                    virt.as_deref().unwrap_or("none"),
+                    cond
+                ));
+            }
         }
+
+        for cond in &self.condition_kernel_command_line {
+            let present = match fs::read_to_string(host.root.join("proc/cmdline")) {
+                Ok(cmdline) => kernel_command_line_matches(&cmdline, &cond.value),
+                Err(e) => {
+                    warn!("Failed to parse /proc/cmdline ({}), ignoring.", e);
+                    false
+                }
+            };
+            if present == cond.negate {
+                return Some(format!(
+                    "kernel command line doesn't satisfy condition-kernel-command-line={}",
+                    cond
+                ));
+            }
+        }
+
+        if let Some(ref cond) = self.condition_architecture {
+            let arch = native_architecture();
+            if (arch == cond.value) == cond.negate {
+                return Some(format!(
+                    "architecture ({}) doesn't satisfy condition-architecture={}",
+                    arch, cond
+                ));
+            }
+        }
+
+        if let Some(ref cond) = self.condition_path_exists {
+            let path = host
+                .root
+                .join(cond.value.strip_prefix("/").unwrap_or(&cond.value));
+            if path.exists() == cond.negate {
+                return Some(format!(
+                    "condition-path-exists={} doesn't hold",
+                    Negatable {
+                        negate: cond.negate,
+                        value: cond.value.display()
+                    }
+                ));
+            }
+        }
+
+        None
     }
 
     pub fn effective_fs_type(&self) -> &str {
@@ -87,8 +446,31 @@ impl Device {
         }
     }
 
-    fn set_disksize_if_enabled(&mut self, memtotal_mb: u64) -> Result<()> {
-        if !self.is_enabled(memtotal_mb) {
+    /// `options`, with the SELinux context appended, for mounting the file system
+    pub fn mount_options(&self) -> Cow<'_, str> {
+        match (&self.selinux_context, &self.options[..]) {
+            (None, _) => Cow::from(&self.options[..]),
+            (Some(context), "") => format!("context=\"{}\"", context).into(),
+            (Some(context), options) => format!("{},context=\"{}\"", options, context).into(),
+        }
+    }
+
+    /// The `x-systemd.*` mount options, without the prefix, e.g. ("before", "builder.service")
+    pub fn x_systemd_options(&self) -> impl Iterator<Item = (&str, &str)> {
+        x_systemd_options(&self.options)
+    }
+
+    /// The N in zramN
+    pub fn index(&self) -> u64 {
+        self.name[4..]
+            .parse()
+            .expect("already verified in read_devices()")
+    }
+
+    /// `count` is the number of configured devices
+    fn set_disksize_if_enabled(&mut self, host: &HostInfo, count: usize) -> Result<()> {
+        let memtotal_mb = host.memtotal_mb;
+        if !self.is_enabled(host) {
             return Ok(());
         }
 
@@ -100,23 +482,185 @@ impl Device {
                 * (1024 * 1024);
         } else {
             self.disksize = (match self.zram_size.as_ref() {
-                Some(zs) => {
-                    zs.1.from(&zs.2.ps)
-                        .eval(&zs.2, &mut RamNs(memtotal_mb as f64))
-                        .with_context(|| format!("{} zram-size", self.name))
-                        .and_then(|f| {
-                            if f >= 0. {
-                                Ok(f)
-                            } else {
-                                Err(anyhow!("{}: zram-size={} < 0", self.name, f))
-                            }
-                        })?
-                }
+                Some(zs) => self.eval_size_expression("zram-size", zs, host, count)?,
                 None => (memtotal_mb as f64 / 2.).min(4096.), // DEFAULT_ZRAM_SIZE
             } * 1024.
                 * 1024.) as u64;
         }
 
+        if let Some(ml) = self.mem_limit.as_ref() {
+            self.mem_limit_bytes =
+                (self.eval_size_expression("mem-limit", ml, host, count)? * 1024. * 1024.) as u64;
+        }
+
+        Ok(())
+    }
+
+    fn eval_size_expression(
+        &self,
+        key: &str,
+        expr: &(String, fasteval::ExpressionI, fasteval::Slab),
+        host: &HostInfo,
+        count: usize,
+    ) -> Result<f64> {
+        expr.1
+            .from(&expr.2.ps)
+            .eval(
+                &expr.2,
+                &mut HostNs {
+                    host,
+                    index: self.index(),
+                    count,
+                },
+            )
+            .with_context(|| format!("{} {}", self.name, key))
+            .and_then(|f| {
+                if f >= 0. {
+                    Ok(f)
+                } else {
+                    Err(anyhow!("{}: {}={} < 0", self.name, key, f))
+                }
+            })
+    }
+
+    /// The section's contents, without the header
+    fn write_config(&self, out: &mut dyn Write) -> io::Result<()> {
+        if self.defined_at.is_none() {
+            writeln!(out, "# created by systemd.zram on the kernel command line")?;
+        }
+        match self.disksize {
+            0 => writeln!(out, "# disksize = 0 (disabled)")?,
+            ds => writeln!(out, "# disksize = {} ({}MB)", ds, ds / 1024 / 1024)?,
+        }
+
+        let mut key = |k: &str, v: &dyn fmt::Display| -> io::Result<()> {
+            match self.origins.get(k) {
+                Some(origin) => writeln!(out, "# {}", origin)?,
+                None => writeln!(out, "# default")?,
+            }
+            writeln!(out, "{} = {}", k, v)
+        };
+
+        key("host-memory-limit", &OptSize(self.host_memory_limit_mb))?;
+        if let Some(minimum_mb) = self.host_memory_minimum_mb {
+            key("host-memory-minimum", &minimum_mb)?;
+        }
+        if let Some((cmp, mb)) = self.condition_memory {
+            key("condition-memory", &format!("{}{}", cmp, mb))?;
+        }
+        if let Some(ref cond) = self.condition_virtualization {
+            key("condition-virtualization", cond)?;
+        }
+        if !self.condition_kernel_command_line.is_empty() {
+            key(
+                "condition-kernel-command-line",
+                &self
+                    .condition_kernel_command_line
+                    .iter()
+                    .map(Negatable::to_string)
+                    .collect::<Vec<_>>()
+                    .join(" "),
+            )?;
+        }
+        if let Some(ref cond) = self.condition_architecture {
+            key("condition-architecture", cond)?;
+        }
+        if let Some(ref cond) = self.condition_path_exists {
+            key(
+                "condition-path-exists",
+                &Negatable {
+                    negate: cond.negate,
+                    value: cond.value.display(),
+                },
+            )?;
+        }
+        key(
+            "zram-size",
+            &self
+                .zram_size
+                .as_ref()
+                .map(|zs| &zs.0[..])
+                .unwrap_or(DEFAULT_ZRAM_SIZE),
+        )?;
+        if let Some(ref ml) = self.mem_limit {
+            key("mem-limit", &ml.0)?;
+        }
+        if self.memory_hotplug != HotplugPolicy::Ignore {
+            key("memory-hotplug", &self.memory_hotplug)?;
+        }
+        if !self.compression_algorithms.is_empty() {
+            key(
+                "compression-algorithm",
+                &self
+                    .compression_algorithms
+                    .iter()
+                    .map(Algorithm::to_string)
+                    .collect::<Vec<_>>()
+                    .join(" "),
+            )?;
+        }
+        if !self.recompression_algorithms.is_empty() {
+            key(
+                "recompression-algorithm",
+                &self
+                    .recompression_algorithms
+                    .iter()
+                    .map(Algorithm::to_string)
+                    .collect::<Vec<_>>()
+                    .join(" "),
+            )?;
+            if let Some(ri) = self.recompression_interval {
+                key("recompression-interval", &format!("{}s", ri))?;
+            }
+            key("recompression-type", &self.recompression_type)?;
+            if let Some(rt) = self.recompression_threshold {
+                key("recompression-threshold", &rt)?;
+            }
+        }
+        if let Some(ref wd) = self.writeback_dev {
+            key("writeback-device", &wd.display())?;
+            if let Some(wi) = self.writeback_interval {
+                key("writeback-interval", &format!("{}s", wi))?;
+            }
+            if let Some(wia) = self.writeback_idle_age {
+                key("writeback-idle-age", &format!("{}s", wia))?;
+            }
+            if let Some(wl) = self.writeback_limit_mb {
+                key("writeback-limit", &wl)?;
+            }
+        }
+        for tunable in TUNABLES {
+            if let Some(value) = self.tunables.get(tunable.key) {
+                key(tunable.key, value)?;
+            }
+        }
+        key("swap-priority", &self.swap_priority)?;
+        if let Some(ref mp) = self.mount_point {
+            key("mount-point", &mp.display())?;
+        }
+        key("fs-type", &self.effective_fs_type())?;
+        key("options", &self.options)?;
+        if let Some(mode) = self.root_mode {
+            key("root-mode", &format!("{:04o}", mode))?;
+        }
+        if let Some(ref owner) = self.root_owner {
+            key("root-owner", owner)?;
+        }
+        if let Some(ref context) = self.selinux_context {
+            key("selinux-context", context)?;
+        }
+        if !self.before.is_empty() {
+            key("before", &self.before.join(" "))?;
+        }
+        if let Some(ref wanted_by) = self.wanted_by {
+            key("wanted-by", &wanted_by.join(" "))?;
+        }
+        if let Some(zf) = self.zram_fraction {
+            key("zram-fraction", &zf)?;
+        }
+        if let Some(mzs) = self.max_zram_size_mb {
+            key("max-zram-size", &OptSize(mzs))?;
+        }
         Ok(())
     }
 }
@@ -132,7 +676,10 @@ impl fmt::Display for Device {
                 .as_ref()
                 .map(|zs| &zs.0[..])
                 .unwrap_or(DEFAULT_ZRAM_SIZE),
-            self.compression_algorithm.as_deref().unwrap_or("<default>"),
+            match &self.compression_algorithms[..] {
+                [] => "<default>".into(),
+                algs => algs.iter().map(Algorithm::to_string).collect::<Vec<_>>().join(" "),
+            },
             self.writeback_dev.as_deref().unwrap_or_else(|| Path::new("<none>")).display(),
             self.options
         )?;
@@ -163,89 +710,858 @@ impl fmt::Display for OptMB {
     }
 }
 
-struct RamNs(f64);
-impl fasteval::EvalNamespace for RamNs {
+/// Like `OptMB`, but in the configuration file syntax
+struct OptSize(Option<u64>);
+impl fmt::Display for OptSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(val) => write!(f, "{}", val),
+            None => f.write_str("none"),
+        }
+    }
+}
+
+/// Properties of the host available to size expressions and conditions, sizes in MB.
+/// Everything but MemTotal is optional; expressions using a missing value fail to evaluate.
+#[derive(Debug, Default)]
+struct HostInfo {
+    /// `/proc`, `/sys`, and `condition-path-exists` paths are relative to this
+    root: PathBuf,
+    memtotal_mb: u64,
+    swap_mb: Option<f64>,
+    mem_available_mb: Option<f64>,
+    hugepages_mb: Option<f64>,
+    cpus: Option<f64>,
+    /// MemTotal of each NUMA node, by node number
+    node_mb: Option<BTreeMap<u64, f64>>,
+    /// Shared by all devices
+    virtualization: Virtualization,
+}
+
+impl HostInfo {
+    fn read(root: &Path, virtualization: Virtualization) -> Result<Self> {
+        let path = root.join("proc/meminfo");
+        let meminfo = read_meminfo(&path)?;
+        let memtotal_mb = get_total_memory_kb(&meminfo, &path)? / 1024;
+        let kb = |field: &str| meminfo.get(field).map(|&kb| kb as f64 / 1024.);
+
+        Ok(HostInfo {
+            root: root.to_path_buf(),
+            memtotal_mb,
+            swap_mb: kb("SwapTotal"),
+            mem_available_mb: kb("MemAvailable"),
+            hugepages_mb: match (meminfo.get("HugePages_Total"), kb("Hugepagesize")) {
+                (Some(&pages), Some(size_mb)) => Some(pages as f64 * size_mb),
+                _ => None,
+            },
+            cpus: read_online_cpus(root).map(|n| n as f64),
+            node_mb: read_node_memory(root),
+            virtualization,
+        })
+    }
+}
+
+/// The output of systemd-detect-virt(1), run on first use
+#[derive(Debug, Default)]
+pub struct Virtualization(RefCell<Option<Option<String>>>);
+
+impl Virtualization {
+    /// Use `output` instead of running systemd-detect-virt, for fake mode
+    pub fn fake(output: &str) -> Self {
+        Virtualization(RefCell::new(Some(parse_detect_virt(output))))
+    }
+
+    /// None if not virtualized, or detection failed
+    pub fn get(&self) -> Option<String> {
+        self.0
+            .borrow_mut()
+            .get_or_insert_with(detect_virtualization)
+            .clone()
+    }
+
+    pub fn is_container(&self) -> bool {
+        virtualization_matches(self.get().as_deref(), "container")
+    }
+}
+
+/// Resolves the variables listed under `zram-size` in zram-generator.conf(5)
+struct HostNs<'a> {
+    host: &'a HostInfo,
+    /// Of the device being evaluated
+    index: u64,
+    /// Of all configured devices
+    count: usize,
+}
+
+impl fasteval::EvalNamespace for HostNs<'_> {
     fn lookup(&mut self, name: &str, args: Vec<f64>, _: &mut String) -> Option<f64> {
-        if name == "ram" && args.is_empty() {
-            Some(self.0)
-        } else {
-            None
+        match (name, &args[..]) {
+            ("ram", []) => Some(self.host.memtotal_mb as f64),
+            ("swap", []) => self.host.swap_mb,
+            ("mem_available", []) => self.host.mem_available_mb,
+            ("hugepages", []) => self.host.hugepages_mb,
+            ("cpus", []) => self.host.cpus,
+            ("nodes", []) => self.host.node_mb.as_ref().map(|n| n.len() as f64),
+            ("node_ram", [node]) if *node >= 0. && node.fract() == 0. => {
+                self.host.node_mb.as_ref()?.get(&(*node as u64)).copied()
+            }
+            ("index", []) => Some(self.index as f64),
+            ("count", []) => Some(self.count as f64),
+            _ => None,
         }
     }
 }
 
-pub fn read_device(root: &Path, kernel_override: bool, name: &str) -> Result<Option<Device>> {
-    let memtotal_mb = get_total_memory_kb(root)? as f64 / 1024.;
-    Ok(read_devices(root, kernel_override, memtotal_mb as u64)?
+pub fn read_device(
+    root: &Path,
+    kernel_override: bool,
+    virtualization: Virtualization,
+    name: &str,
+) -> Result<Option<Device>> {
+    let host = HostInfo::read(root, virtualization)?;
+    Ok(read_devices(root, kernel_override, &host)?
+        .devices
         .remove(name)
         .filter(|dev| dev.disksize > 0))
 }
 
-pub fn read_all_devices(root: &Path, kernel_override: bool) -> Result<Vec<Device>> {
-    let memtotal_mb = get_total_memory_kb(root)? as f64 / 1024.;
-    Ok(read_devices(root, kernel_override, memtotal_mb as u64)?
-        .into_iter()
-        .filter(|(_, dev)| dev.disksize > 0)
-        .map(|(_, dev)| dev)
-        .collect())
+pub fn read_all_devices(
+    root: &Path,
+    kernel_override: bool,
+    virtualization: Virtualization,
+) -> Result<Vec<Device>> {
+    Ok(read_config(root, kernel_override, virtualization)?.devices)
 }
 
-fn read_devices(
+pub fn read_config(
     root: &Path,
     kernel_override: bool,
-    memtotal_mb: u64,
-) -> Result<HashMap<String, Device>> {
+    virtualization: Virtualization,
+) -> Result<Config> {
+    let host = HostInfo::read(root, virtualization)?;
+    let Parsed {
+        devices, sysctl, ..
+    } = read_devices(root, kernel_override, &host)?;
+    Ok(Config {
+        devices: devices
+            .into_iter()
+            .filter(|(_, dev)| dev.disksize > 0)
+            .map(|(_, dev)| dev)
+            .collect(),
+        sysctl: sysctl.into_values().collect(),
+        virtualization: host.virtualization,
+    })
+}
+
+fn read_devices(root: &Path, kernel_override: bool, host: &HostInfo) -> Result<Parsed> {
     let fragments = locate_fragments(root);
 
     if fragments.is_empty() && !kernel_override {
         info!("No configuration found.");
     }
 
+    let mut parsed = configure_devices(fragments.values(), kernel_override, &mut |err| match err
+        .severity
+    {
+        Severity::Skipped => error!(
+            config_file:% = err.path.display(),
+            config_line = err.line,
+            config_section = err.section.as_deref(),
+            config_key = err.key.as_deref(),
+            message_id = message_id::CONFIG_SKIPPED;
+            "{}, skipping {}.", err, match err.section {
+                Some(ref section) => format!("section [{}]", section),
+                None => "file".to_string(),
+            }
+        ),
+        _ => warn!(
+            config_file:% = err.path.display(),
+            config_line = err.line,
+            config_section = err.section.as_deref(),
+            config_key = err.key.as_deref(),
+            message_id = message_id::CONFIG_IGNORED;
+            "{}, ignoring.", err
+        ),
+    })?;
+
+    if let Some((false, _)) = parsed.enabled {
+        // systemd.zram=1 still creates zram0
+        parsed
+            .devices
+            .retain(|name, _| kernel_override && name == "zram0");
+        info!("Disabled by configuration.");
+    }
+
+    let count = parsed.devices.len();
+    parsed
+        .devices
+        .retain(|_, dev| match dev.set_disksize_if_enabled(host, count) {
+            Ok(()) => true,
+            Err(e) => {
+                error!(zram_device = dev.name.as_str(); "{:#}, skipping device.", e);
+                false
+            }
+        });
+
+    Ok(parsed)
+}
+
+/// Everything read from the configuration fragments
+struct Parsed {
+    devices: HashMap<String, Device>,
+    sysctl: BTreeMap<String, Sysctl>,
+    /// `enabled` from `[zram-generator]`, if set, and where
+    enabled: Option<(bool, Option<Origin>)>,
+}
+
+/// Reads the devices and global settings defined in `paths`, in order of increasing precedence,
+/// without sizing the devices; problems are passed to `report`,
+/// and the offending lines, sections or files skipped
+fn configure_devices<'a>(
+    paths: impl IntoIterator<Item = &'a PathBuf>,
+    kernel_override: bool,
+    report: &mut dyn FnMut(ConfigError),
+) -> Result<Parsed> {
+    let mut sysctl = BTreeMap::new();
+    let mut enabled = None;
     let mut devices: HashMap<String, Device> = HashMap::new();
+    let mut add_device = |num: u64, defined_at: &Option<Origin>| {
+        let name = format!("zram{}", num);
+        devices.entry(name.clone()).or_insert_with(|| Device {
+            defined_at: defined_at.clone(),
+            ..Device::new(name)
+        });
+    };
+    let mut settings = vec![];
+
+    for (file, path) in paths.into_iter().enumerate() {
+        let problem =
+            |section: Option<&str>, key: Option<&str>, line, severity, cause| ConfigError {
+                path: path.clone(),
+                section: section.map(str::to_string),
+                key: key.map(str::to_string),
+                line,
+                severity,
+                cause,
+            };
 
-    for (_, path) in fragments {
-        let ini = Ini::load_from_file(&path)?;
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) => {
+                report(problem(
+                    None,
+                    None,
+                    None,
+                    Severity::Skipped,
+                    anyhow::Error::new(e).context("Failed to read configuration"),
+                ));
+                continue;
+            }
+        };
+        let lines = locate_lines(&text);
+        let mut section_seen: HashMap<String, usize> = HashMap::new();
+        let mut key_seen: HashMap<(String, String), usize> = HashMap::new();
+        let ini = match Ini::load_from_str(&text) {
+            Ok(ini) => ini,
+            Err(e) => {
+                report(problem(
+                    None,
+                    None,
+                    Some(e.line + 1),
+                    Severity::Skipped,
+                    anyhow!("column {}: {}", e.col + 1, e.msg),
+                ));
+                continue;
+            }
+        };
 
         for (sname, props) in ini.iter() {
-            let sname = match sname {
+            let (sname, selector, section_line) = match sname {
                 None => {
-                    warn!(
-                        "{}: ignoring settings outside of section: {:?}",
-                        path.display(),
-                        props
-                    );
+                    report(problem(
+                        None,
+                        None,
+                        lines.general.first().copied(),
+                        Severity::Ignored,
+                        anyhow!(
+                            "settings outside of section: {}",
+                            props.iter().map(|(k, _)| k).collect::<Vec<_>>().join(", ")
+                        ),
+                    ));
                     continue;
                 }
-                Some(sname) if sname.starts_with("zram") && sname[4..].parse::<u64>().is_ok() => {
-                    sname.to_string()
-                }
                 Some(sname) => {
-                    warn!("{}: Ignoring section \"{}\"", path.display(), sname);
-                    continue;
+                    let seen = section_seen.entry(sname.to_string()).or_insert(0);
+                    let line = lines.sections.get(sname).and_then(|l| l.get(*seen));
+                    *seen += 1;
+
+                    match Selector::parse(sname) {
+                        _ if sname == "sysctl" => (sname, None, line.copied()),
+                        Ok(Some(selector)) => (sname, Some(selector), line.copied()),
+                        Err(e) => {
+                            report(problem(
+                                Some(sname),
+                                None,
+                                line.copied(),
+                                Severity::Skipped,
+                                e,
+                            ));
+                            continue;
+                        }
+                        Ok(None) => {
+                            report(problem(
+                                Some(sname),
+                                None,
+                                line.copied(),
+                                Severity::Ignored,
+                                anyhow!("unknown section \"{}\"", sname),
+                            ));
+                            continue;
+                        }
+                    }
                 }
             };
 
-            let dev = devices
-                .entry(sname.clone())
-                .or_insert_with(|| Device::new(sname));
+            // A section with a bad value is skipped as a whole, as if it weren't there
+            let mut broken = false;
+            let mut section_enabled = None;
+            let mut section_settings = vec![];
 
             for (k, v) in props.iter() {
-                parse_line(dev, k, v)?;
+                let key = (sname.to_string(), k.to_string());
+                let seen = key_seen.entry(key.clone()).or_insert(0);
+                let line = lines.keys.get(&key).and_then(|l| l.get(*seen)).copied();
+                *seen += 1;
+                let origin = line.map(|line| Origin {
+                    path: path.clone(),
+                    line,
+                });
+                let problem =
+                    |severity, cause| problem(Some(sname), Some(k), line, severity, cause);
+
+                let selector = match selector {
+                    Some(Selector::Defaults) if k == "enabled" => {
+                        match parse_bool(v) {
+                            Ok(value) => section_enabled = Some((value, origin)),
+                            Err(e) => {
+                                report(problem(Severity::Skipped, e));
+                                broken = true;
+                            }
+                        }
+                        continue;
+                    }
+                    Some(selector) => selector,
+                    None => {
+                        match parse_sysctl_name(k) {
+                            Err(e) => report(problem(Severity::Ignored, e)),
+                            Ok(name) if v.is_empty() => {
+                                sysctl.remove(&name);
+                            }
+                            Ok(name) => {
+                                sysctl.insert(
+                                    name.clone(),
+                                    Sysctl {
+                                        name,
+                                        value: v.to_string(),
+                                        origin,
+                                    },
+                                );
+                            }
+                        }
+                        continue;
+                    }
+                };
+
+                // Check the value once here, rather than for each device it applies to
+                if let Err(e) = parse_line(&mut Device::new(sname.to_string()), k, v) {
+                    if e.is::<UnknownKey>() {
+                        report(problem(Severity::Ignored, e));
+                    } else {
+                        report(problem(Severity::Skipped, e));
+                        broken = true;
+                    }
+                    continue;
+                }
+
+                section_settings.push((file, selector, k.to_string(), v.to_string(), origin));
+            }
+
+            if broken {
+                continue;
             }
+
+            // Explicit and range sections define devices, templates and defaults only configure them
+            let defined_at = section_line.map(|line| Origin {
+                path: path.clone(),
+                line,
+            });
+            match selector {
+                Some(Selector::One(num)) => add_device(num, &defined_at),
+                Some(Selector::Range(from, to)) => {
+                    (from..=to).for_each(|num| add_device(num, &defined_at))
+                }
+                Some(Selector::All) | Some(Selector::Defaults) | None => {}
+            }
+            if section_enabled.is_some() {
+                enabled = section_enabled;
+            }
+            settings.append(&mut section_settings);
         }
     }
 
     if kernel_override {
-        devices
-            .entry("zram0".to_string())
-            .or_insert_with(|| Device::new("zram0".to_string()));
+        add_device(0, &None);
+    }
+
+    // Within a fragment, more specific sections override less specific ones, regardless of order,
+    // while later fragments override earlier ones; only the defaults come before everything
+    settings.sort_by_key(|&(file, selector, ..)| match selector {
+        Selector::Defaults => (0, file, 0),
+        selector => (1, file, selector.precedence()),
+    });
+    for (_, selector, k, v, origin) in settings {
+        for dev in devices.values_mut() {
+            if !selector.matches(dev.index()) {
+                continue;
+            }
+
+            parse_line(dev, &k, &v)?;
+            if let Some(ref origin) = origin {
+                dev.origins
+                    .insert(canonical_key(&k).to_string(), origin.clone());
+            }
+        }
+    }
+
+    Ok(Parsed {
+        devices,
+        sysctl,
+        enabled,
+    })
+}
+
+fn parse_bool(value: &str) -> Result<bool> {
+    match value {
+        "1" | "yes" | "true" | "on" => Ok(true),
+        "0" | "no" | "false" | "off" => Ok(false),
+        _ => Err(anyhow!("\"{}\" is not a boolean", value)),
+    }
+}
+
+/// Only the VM settings relevant to swap may be set, with either separator
+fn parse_sysctl_name(key: &str) -> Result<String> {
+    let name = key.replace('/', ".");
+    match name.strip_prefix("vm.") {
+        Some(rest)
+            if !rest.is_empty()
+                && rest.bytes().all(|b| {
+                    b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'_' || b == b'-'
+                }) =>
+        {
+            Ok(name)
+        }
+        _ => Err(anyhow!(
+            "unsupported sysctl {}, only vm.* settings may be set",
+            key
+        )),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Severity {
+    /// Only reported by `--verify`, without affecting its exit status
+    Advisory,
+    /// The offending line or section is ignored
+    Ignored,
+    /// The whole section or file is skipped, along with the devices only it defines
+    Skipped,
+    /// The configuration can't be used as intended
+    Fatal,
+}
+
+/// A problem with a configuration fragment
+#[derive(Debug)]
+pub struct ConfigError {
+    pub path: PathBuf,
+    /// None if not attributable to a single section
+    pub section: Option<String>,
+    /// None if not attributable to a single key
+    pub key: Option<String>,
+    /// None if not attributable to a single line
+    pub line: Option<usize>,
+    pub severity: Severity,
+    pub cause: anyhow::Error,
+}
+
+impl ConfigError {
+    pub fn location(&self) -> String {
+        match self.line {
+            Some(line) => format!("{}:{}", self.path.display(), line),
+            None => self.path.display().to_string(),
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {:#}", self.location(), self.cause)
+    }
+}
+
+/// Returned by `parse_line()` for keys it doesn't recognise
+#[derive(Debug)]
+struct UnknownKey(String);
+
+impl fmt::Display for UnknownKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unknown key {}", self.0)
+    }
+}
+
+impl std::error::Error for UnknownKey {}
+
+/// The devices a configuration section applies to
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Selector {
+    /// `[zram-generator]`: every device defined elsewhere, with the lowest precedence
+    Defaults,
+    /// `[zram*]`: every device defined elsewhere
+    All,
+    /// `[zramN-M]`: devices N through M, inclusive
+    Range(u64, u64),
+    /// `[zramN]`
+    One(u64),
+}
+
+/// The most devices a `[zramN-M]` section may define, so that a typo can't create billions of them
+const MAX_RANGE_DEVICES: u64 = 256;
+
+impl Selector {
+    /// None if `section` isn't about devices at all
+    fn parse(section: &str) -> Result<Option<Self>> {
+        if section == "zram-generator" {
+            return Ok(Some(Selector::Defaults));
+        }
+        let spec = match section.strip_prefix("zram") {
+            Some(spec) => spec,
+            None => return Ok(None),
+        };
+        if spec == "*" {
+            return Ok(Some(Selector::All));
+        }
+
+        Ok(match spec.split_once('-') {
+            Some((from, to)) => match (from.parse::<u64>(), to.parse::<u64>()) {
+                (Ok(from), Ok(to)) if from <= to && to - from < MAX_RANGE_DEVICES => {
+                    Some(Selector::Range(from, to))
+                }
+                (Ok(from), Ok(to)) if from <= to => {
+                    return Err(anyhow!(
+                        "invalid range \"{}\": more than {} devices",
+                        section,
+                        MAX_RANGE_DEVICES
+                    ))
+                }
+                (Ok(from), Ok(to)) => {
+                    return Err(anyhow!(
+                        "invalid range \"{}\": zram{} comes after zram{}",
+                        section,
+                        from,
+                        to
+                    ))
+                }
+                _ => None,
+            },
+            None => spec.parse().ok().map(Selector::One),
+        })
+    }
+
+    fn matches(&self, num: u64) -> bool {
+        match *self {
+            Selector::Defaults | Selector::All => true,
+            Selector::Range(from, to) => (from..=to).contains(&num),
+            Selector::One(n) => n == num,
+        }
+    }
+
+    fn precedence(&self) -> u8 {
+        match self {
+            Selector::Defaults => 0,
+            Selector::All => 1,
+            Selector::Range(..) => 2,
+            Selector::One(_) => 3,
+        }
+    }
+}
+
+/// 1-based line numbers of each section header and assignment, in order
+#[derive(Debug, Default)]
+struct Lines {
+    sections: HashMap<String, Vec<usize>>,
+    keys: HashMap<(String, String), Vec<usize>>,
+    /// assignments outside of any section
+    general: Vec<usize>,
+}
+
+fn locate_lines(text: &str) -> Lines {
+    let mut ret = Lines::default();
+    let mut section = None;
+
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.starts_with('[') && line.ends_with(']') {
+            let name = line[1..line.len() - 1].trim().to_string();
+            ret.sections.entry(name.clone()).or_default().push(i + 1);
+            section = Some(name);
+        } else if line.starts_with('#') || line.starts_with(';') {
+            continue;
+        } else if let Some(idx) = line.find(&['=', ':'][..]) {
+            match section.as_ref() {
+                Some(section) => ret
+                    .keys
+                    .entry((section.clone(), line[..idx].trim().to_string()))
+                    .or_default()
+                    .push(i + 1),
+                None => ret.general.push(i + 1),
+            }
+        }
+    }
+
+    ret
+}
+
+fn canonical_key(key: &str) -> &str {
+    match key {
+        "memory-limit" => "host-memory-limit",
+        key => key,
+    }
+}
+
+/// Write the effective configuration of all devices, one INI section each,
+/// noting where every key was set
+pub fn dump_config(
+    root: &Path,
+    kernel_override: bool,
+    virtualization: Virtualization,
+    out: &mut dyn Write,
+) -> Result<()> {
+    let host = HostInfo::read(root, virtualization)?;
+    let Parsed {
+        devices,
+        sysctl,
+        enabled,
+    } = read_devices(root, kernel_override, &host)?;
+    let mut devices: Vec<_> = devices.into_values().collect();
+    devices.sort_by_key(Device::index);
+
+    let mut first = true;
+    let mut section = |out: &mut dyn Write, name: &str| {
+        if !std::mem::take(&mut first) {
+            writeln!(out)?;
+        }
+        writeln!(out, "[{}]", name)
+    };
+
+    if let Some((enabled, ref origin)) = enabled {
+        section(out, "zram-generator")?;
+        match origin {
+            Some(origin) => writeln!(out, "# {}", origin)?,
+            None => writeln!(out, "# default")?,
+        }
+        writeln!(out, "enabled = {}", if enabled { "yes" } else { "no" })?;
     }
 
-    for dev in devices.values_mut() {
-        dev.set_disksize_if_enabled(memtotal_mb)?;
+    for dev in &devices {
+        section(out, &dev.name)?;
+        dev.write_config(out)?;
+    }
+
+    if !sysctl.is_empty() {
+        section(out, "sysctl")?;
+        for setting in sysctl.values() {
+            match setting.origin {
+                Some(ref origin) => writeln!(out, "# {}", origin)?,
+                None => writeln!(out, "# default")?,
+            }
+            writeln!(out, "{} = {}", setting.name, setting.value)?;
+        }
+    }
+    Ok(())
+}
+
+/// Total RAM sizes, in MB, `--verify` evaluates size expressions for
+const VERIFY_RAM_SIZES_MB: &[u64] = &[
+    256, 512, 1024, 2048, 4096, 8192, 16384, 32768, 65536, 262144, 1048576,
+];
+
+/// Variables whose availability depends on the machine running `--verify`
+const HOST_VARIABLES: &[&str] = &[
+    "swap",
+    "mem_available",
+    "hugepages",
+    "cpus",
+    "nodes",
+    "node_ram",
+];
+
+/// Check all fragments, or just `files`, for problems, in addition to ones which prevent their use:
+/// size expressions failing to evaluate for any of `VERIFY_RAM_SIZES_MB` or using deprecated SI suffixes,
+/// compression algorithms missing from /proc/crypto,
+/// and mount points or writeback devices used by more than one device
+pub fn verify_config(root: &Path, files: &[PathBuf]) -> Result<Vec<ConfigError>> {
+    let paths: Vec<_> = match files {
+        [] => locate_fragments(root).into_values().collect(),
+        files => files.to_vec(),
+    };
+
+    let mut problems = vec![];
+    let Parsed { devices, .. } = configure_devices(&paths, false, &mut |err| problems.push(err))?;
+    let mut devices: Vec<_> = devices.into_values().collect();
+    devices.sort_by_key(Device::index);
+
+    // Conditions aren't checked, so this is never detected
+    let mut host = HostInfo::read(root, Virtualization::default())?;
+    let crypto = read_crypto_algorithms(root);
+    let mut mount_points = HashMap::new();
+    let mut writeback_devs = HashMap::new();
+
+    for dev in &devices {
+        let mut report = |key: &str, severity, cause| {
+            // Point at the line setting the key, failing that at the section defining the device,
+            // and only name the device if neither is known
+            let (path, line) = match dev.origins.get(key).or(dev.defined_at.as_ref()) {
+                Some(origin) => (origin.path.clone(), Some(origin.line)),
+                None => (PathBuf::from(&dev.name), None),
+            };
+            problems.push(ConfigError {
+                path,
+                section: None,
+                key: Some(key.to_string()),
+                line,
+                severity,
+                cause,
+            });
+        };
+
+        for (key, expr) in [("zram-size", &dev.zram_size), ("mem-limit", &dev.mem_limit)] {
+            let expr = match expr {
+                Some(expr) => expr,
+                None => continue,
+            };
+            if let Err(e) = check_expression_units(&expr.0) {
+                report(key, Severity::Advisory, e.context("deprecated"));
+            }
+            for &ram in VERIFY_RAM_SIZES_MB {
+                host.memtotal_mb = ram;
+                if let Err(e) = dev.eval_size_expression(key, expr, &host, devices.len()) {
+                    match e.root_cause().downcast_ref() {
+                        Some(fasteval::Error::Undefined(var))
+                            if HOST_VARIABLES.contains(&var.as_str()) =>
+                        {
+                            report(
+                                key,
+                                Severity::Advisory,
+                                anyhow!("{} is not available on this machine", var),
+                            )
+                        }
+                        _ => report(key, Severity::Skipped, anyhow!("{:#} (ram = {})", e, ram)),
+                    }
+                    break;
+                }
+            }
+        }
+
+        if let Some(ref crypto) = crypto {
+            // Fallbacks are expected to be missing on some kernels, so only complain if all are
+            let primary = match &dev.compression_algorithms[..] {
+                algs if algs.iter().any(|alg| crypto.contains(&alg.name)) => None,
+                [] => None,
+                [alg] => Some(("compression-algorithm", &alg.name)),
+                algs => {
+                    report(
+                        "compression-algorithm",
+                        Severity::Advisory,
+                        anyhow!(
+                            "none of compression algorithms {} found in /proc/crypto",
+                            algs.iter()
+                                .map(|alg| &alg.name[..])
+                                .collect::<Vec<_>>()
+                                .join(", ")
+                        ),
+                    );
+                    None
+                }
+            };
+            let algorithms = primary.into_iter().chain(
+                dev.recompression_algorithms
+                    .iter()
+                    .map(|alg| ("recompression-algorithm", &alg.name)),
+            );
+            for (key, alg) in algorithms {
+                if !crypto.contains(alg) {
+                    report(
+                        key,
+                        Severity::Advisory,
+                        anyhow!("compression algorithm {} not found in /proc/crypto", alg),
+                    );
+                }
+            }
+        }
+
+        if let Some(ref mp) = dev.mount_point {
+            if let Some(other) = mount_points.insert(mp, &dev.name) {
+                report(
+                    "mount-point",
+                    Severity::Fatal,
+                    anyhow!(
+                        "{}: mount point {} also used by {}",
+                        dev.name,
+                        mp.display(),
+                        other
+                    ),
+                );
+            }
+        }
+
+        if let Some(ref wd) = dev.writeback_dev {
+            if let Some(other) = writeback_devs.insert(wd, &dev.name) {
+                report(
+                    "writeback-device",
+                    Severity::Fatal,
+                    anyhow!(
+                        "{}: writeback device {} also used by {}",
+                        dev.name,
+                        wd.display(),
+                        other
+                    ),
+                );
+            }
+        }
     }
 
-    Ok(devices)
+    // Template sections report the same problem for each device
+    let mut seen = BTreeSet::new();
+    problems.retain(|p| seen.insert(p.to_string()));
+    problems.sort_by_key(|p| (paths.iter().position(|path| *path == p.path), p.line));
+    Ok(problems)
+}
+
+/// Names and driver names of the algorithms in /proc/crypto, None if it can't be read
+fn read_crypto_algorithms(root: &Path) -> Option<BTreeSet<String>> {
+    let path = root.join("proc/crypto");
+    match fs::read_to_string(&path) {
+        Ok(text) => Some(
+            text.lines()
+                .filter_map(|l| l.split_once(':'))
+                .filter(|(k, _)| matches!(k.trim(), "name" | "driver"))
+                .map(|(_, v)| v.trim().to_string())
+                .collect(),
+        ),
+        Err(e) => {
+            debug!("Failed to read {}: {}", path.display(), e);
+            None
+        }
+    }
 }
 
 fn locate_fragments(root: &Path) -> BTreeMap<String, PathBuf> {
@@ -281,17 +1597,306 @@ fn locate_fragments(root: &Path) -> BTreeMap<String, PathBuf> {
     fragments
 }
 
+/// Unit suffixes accepted in sizes, all powers of 1024, in MiB; a bare number is in MiB
+const SIZE_UNITS: &[(&str, f64)] = &[
+    ("K", 1. / 1024.),
+    ("KiB", 1. / 1024.),
+    ("M", 1.),
+    ("MiB", 1.),
+    ("G", 1024.),
+    ("GiB", 1024.),
+    ("T", 1024. * 1024.),
+    ("TiB", 1024. * 1024.),
+];
+
+/// Parses a number with an optional unit suffix, like "8G" or "1.5 GiB", into MiB;
+/// None if `val` isn't of that form, and so may be an expression
+fn parse_size_unit(val: &str) -> Option<Result<f64>> {
+    let val = val.trim();
+    let digits = val
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(val.len());
+    let num: f64 = val[..digits].parse().ok()?;
+    let unit = val[digits..].trim_start();
+    if unit.is_empty() {
+        return Some(Ok(num));
+    }
+    if !unit.bytes().all(|b| b.is_ascii_alphabetic()) {
+        return None;
+    }
+
+    // "GB" could mean 10⁹ or 2³⁰ bytes, "g" grams, so accept only unambiguous units
+    Some(match SIZE_UNITS.iter().find(|(u, _)| *u == unit) {
+        Some((_, mib)) => Ok(num * mib),
+        None => Err(anyhow!(
+            "ambiguous unit \"{}\" in size \"{}\", use K, M, G, T, or KiB, MiB, GiB, TiB",
+            unit,
+            val
+        )),
+    })
+}
+
+/// fasteval reads "4G" in an expression as 4×10⁹, which as a size in MiB is never what was meant,
+/// so only a plain number may have a unit; `zram-size` and `mem-limit` still accept them, deprecated
+fn check_expression_units(val: &str) -> Result<()> {
+    let suffixed = val.as_bytes().windows(2).any(|w| {
+        (w[0].is_ascii_digit() || w[0] == b'.')
+            && w[1].is_ascii_alphabetic()
+            && !matches!(w[1], b'e' | b'E')
+    });
+    match suffixed {
+        true => Err(anyhow!(
+            "SI suffix in expression \"{}\" multiplies by a power of 10, write sizes in MiB instead",
+            val
+        )),
+        false => Ok(()),
+    }
+}
+
+/// A size in whole MiB, rounded up: a number with an optional unit suffix, or a constant expression
+fn parse_size(val: &str) -> Result<u64> {
+    let mib = match parse_size_unit(val) {
+        Some(mib) => mib?,
+        None if val.trim_end().ends_with('%') => {
+            return Err(anyhow!(
+                "size \"{}\" is a percentage, which only zram-size and mem-limit accept",
+                val
+            ))
+        }
+        None => {
+            check_expression_units(val)?;
+            fasteval::ez_eval(val, &mut fasteval::EmptyNamespace)
+                .with_context(|| format!("Failed to parse size \"{}\"", val))?
+        }
+    };
+
+    // So that e.g. 512K doesn't become 0, which means "no limit" or "no device" for some keys
+    if mib.is_finite() && mib >= 0. {
+        Ok(mib.ceil() as u64)
+    } else {
+        Err(anyhow!("size \"{}\" = {} is not a valid size", val, mib))
+    }
+}
+
 fn parse_optional_size(val: &str) -> Result<Option<u64>> {
     Ok(if val == "none" {
         None
     } else {
-        Some(
-            val.parse()
-                .with_context(|| format!("Failed to parse optional size \"{}\"", val))?,
-        )
+        Some(parse_size(val)?)
     })
 }
 
+fn parse_negatable(val: &str) -> Negatable<String> {
+    match val.strip_prefix('!') {
+        Some(rest) => Negatable {
+            negate: true,
+            value: rest.trim_start().to_string(),
+        },
+        None => Negatable {
+            negate: false,
+            value: val.to_string(),
+        },
+    }
+}
+
+/// An optional comparison operator (">=" if omitted), followed by a size in MB
+fn parse_memory_condition(val: &str) -> Result<(Comparison, u64)> {
+    let (cmp, size) = Comparison::ALL
+        .iter()
+        .find_map(|&(op, cmp)| val.strip_prefix(op).map(|rest| (cmp, rest)))
+        .unwrap_or((Comparison::GreaterEqual, val));
+    Ok((cmp, parse_size(size)?))
+}
+
+/// A subset of systemd.time(7) time spans, in whole seconds
+fn parse_timespan(val: &str) -> Result<u64> {
+    let mut rest = val.trim();
+    if rest.is_empty() {
+        return Err(anyhow!("Empty time span"));
+    }
+
+    let mut total: u64 = 0;
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let num: u64 = rest[..digits]
+            .parse()
+            .with_context(|| format!("Failed to parse time span \"{}\"", val))?;
+        rest = rest[digits..].trim_start();
+
+        let unit_len = rest
+            .find(|c: char| !c.is_ascii_alphabetic())
+            .unwrap_or(rest.len());
+        let multiplier = match &rest[..unit_len] {
+            "" | "s" | "sec" | "second" | "seconds" => 1,
+            "m" | "min" | "minute" | "minutes" => 60,
+            "h" | "hr" | "hour" | "hours" => 60 * 60,
+            "d" | "day" | "days" => 24 * 60 * 60,
+            "w" | "week" | "weeks" => 7 * 24 * 60 * 60,
+            unit => return Err(anyhow!("Unknown time unit \"{}\" in \"{}\"", unit, val)),
+        };
+        rest = rest[unit_len..].trim_start();
+
+        total = num
+            .checked_mul(multiplier)
+            .and_then(|n| total.checked_add(n))
+            .ok_or_else(|| anyhow!("Time span \"{}\" too large", val))?;
+    }
+    Ok(total)
+}
+
+/// Whitespace-separated `name` or `name(key=value,…)` items
+fn parse_algorithms(val: &str) -> Result<Vec<Algorithm>> {
+    let mut items = vec![];
+    let mut depth = 0;
+    let mut start = None;
+    for (i, c) in val.char_indices().chain(Some((val.len(), ' '))) {
+        match c {
+            '(' => depth += 1,
+            ')' if depth == 0 => return Err(anyhow!("Unbalanced ')' in \"{}\"", val)),
+            ')' => depth -= 1,
+            c if c.is_whitespace() && depth == 0 => {
+                if let Some(s) = start.take() {
+                    items.push(&val[s..i]);
+                }
+                continue;
+            }
+            _ => {}
+        }
+        start.get_or_insert(i);
+    }
+    if depth != 0 {
+        return Err(anyhow!("Unbalanced '(' in \"{}\"", val));
+    }
+
+    items
+        .into_iter()
+        .map(|item| {
+            let (name, params) = match item.find('(') {
+                None => (item, vec![]),
+                Some(idx) if item.ends_with(')') => (
+                    &item[..idx],
+                    item[idx + 1..item.len() - 1]
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|p| !p.is_empty())
+                        .map(|p| match p.split_once('=') {
+                            Some((k, v)) if !k.trim().is_empty() => {
+                                Ok((k.trim().to_string(), v.trim().to_string()))
+                            }
+                            _ => Err(anyhow!("Parameter \"{}\" of {} not key=value", p, item)),
+                        })
+                        .collect::<Result<_>>()?,
+                ),
+                Some(_) => return Err(anyhow!("Trailing garbage after parameters in {}", item)),
+            };
+            if name.is_empty() {
+                return Err(anyhow!("Missing algorithm name in {}", item));
+            }
+            Ok(Algorithm {
+                name: name.to_string(),
+                params,
+            })
+        })
+        .collect()
+}
+
+fn parse_tunable(tunable: &Tunable, value: &str) -> Result<u64> {
+    match value.parse() {
+        Ok(v) if v >= tunable.min => Ok(v),
+        Ok(v) => Err(anyhow!("{}={} < {}", tunable.key, v, tunable.min)),
+        Err(e) => Err(e).with_context(|| format!("Failed to parse {} \"{}\"", tunable.key, value)),
+    }
+}
+
+/// Fallbacks for the primary algorithm, in order of preference
+fn parse_compression_algorithms(val: &str) -> Result<Vec<Algorithm>> {
+    let algs = parse_algorithms(val)?;
+    for alg in &algs {
+        for (k, v) in &alg.params {
+            verify_algorithm_param(alg, k, v)?;
+        }
+    }
+    Ok(algs)
+}
+
+/// Parameters written to algorithm_params, other than priority
+fn verify_algorithm_param(alg: &Algorithm, key: &str, value: &str) -> Result<()> {
+    match key {
+        "level" => {
+            value
+                .parse::<i32>()
+                .with_context(|| format!("{}: level {} not an integer", alg.name, value))?;
+        }
+        "dict" => {
+            if !Path::new(value).is_absolute() {
+                return Err(anyhow!("{}: dict {} not an absolute path", alg.name, value));
+            }
+        }
+        _ => return Err(anyhow!("{}: unknown parameter {}", alg.name, key)),
+    }
+    Ok(())
+}
+
+/// Up to `ZRAM_MAX_COMPS - 1` secondary algorithms
+fn parse_recompression_algorithms(val: &str) -> Result<Vec<Algorithm>> {
+    let algs = parse_algorithms(val)?;
+    if algs.len() > 3 {
+        return Err(anyhow!("Too many recompression algorithms in \"{}\"", val));
+    }
+
+    // The kernel silently replaces an algorithm with another one of the same priority
+    let mut priorities = BTreeMap::new();
+    for (i, alg) in algs.iter().enumerate() {
+        let mut priority = i as u64 + 1;
+        for (k, v) in &alg.params {
+            match &k[..] {
+                "priority" => match v.parse() {
+                    Ok(p @ 1..=3) => priority = p,
+                    _ => return Err(anyhow!("{}: priority {} not in 1..=3", alg.name, v)),
+                },
+                _ => verify_algorithm_param(alg, k, v)?,
+            }
+        }
+        if let Some(other) = priorities.insert(priority, &alg.name) {
+            return Err(anyhow!(
+                "{}: priority {} already used by {}",
+                alg.name,
+                priority,
+                other
+            ));
+        }
+    }
+    Ok(algs)
+}
+
+fn parse_size_expression(
+    dev_name: &str,
+    key: &str,
+    val: &str,
+) -> Result<(String, fasteval::ExpressionI, fasteval::Slab)> {
+    // Sizes with units and percentages of RAM are shorthands for expressions in MiB
+    let expr = match parse_size_unit(val) {
+        Some(mib) => mib
+            .with_context(|| format!("{} {}", dev_name, key))?
+            .to_string(),
+        None => match val.trim_end().strip_suffix('%') {
+            Some(percent) => format!("ram * ({}) / 100", percent),
+            None => val.to_string(),
+        },
+    };
+
+    let mut sl = fasteval::Slab::new();
+    Ok((
+        val.to_string(),
+        fasteval::Parser::new()
+            .parse_noclear(&expr, &mut sl.ps)
+            .with_context(|| format!("{} {}", dev_name, key))?,
+        sl,
+    ))
+}
+
 fn parse_swap_priority(val: &str) -> Result<i32> {
     let val = val
         .parse()
@@ -304,18 +1909,100 @@ fn parse_swap_priority(val: &str) -> Result<i32> {
     }
 }
 
-fn verify_mount_point(key: &str, val: &str) -> Result<PathBuf> {
-    let path = Path::new(val);
+fn verify_mount_point(key: &str, val: &str) -> Result<PathBuf> {
+    let path = Path::new(val);
+
+    if path.is_relative() {
+        return Err(anyhow!("{} {} is not absolute", key, val));
+    }
+
+    if path.components().any(|c| c == Component::ParentDir) {
+        return Err(anyhow!("{} {:#?} is not normalized", key, path));
+    }
+
+    Ok(path.components().collect()) // normalise away /./ components
+}
+
+/// An octal mode, as for chmod(1), e.g. "1777"
+fn parse_mode(val: &str) -> Result<u32> {
+    match u32::from_str_radix(val, 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(mode),
+        _ => Err(anyhow!(
+            "Failed to parse mode \"{}\", expected octal 0000..7777",
+            val
+        )),
+    }
+}
+
+/// "user", "user:group", or ":group", by name or number, as for chown(1)
+fn verify_owner(val: &str) -> Result<&str> {
+    let valid_name = |name: &str| {
+        !name.is_empty()
+            && name
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b"_-.$".contains(&b))
+    };
+    let valid = match val.split_once(':') {
+        None => valid_name(val),
+        Some(("", group)) => valid_name(group),
+        Some((user, group)) => valid_name(user) && valid_name(group),
+    };
+    match valid {
+        true => Ok(val),
+        false => Err(anyhow!("Invalid owner \"{}\", expected user[:group]", val)),
+    }
+}
 
-    if path.is_relative() {
-        return Err(anyhow!("{} {} is not absolute", key, val));
+/// The context is quoted in the mount options, since MLS ranges may contain commas
+fn verify_selinux_context(val: &str) -> Result<&str> {
+    match val.contains(|c: char| c == '"' || c.is_whitespace()) || val.split(':').count() < 3 {
+        true => Err(anyhow!(
+            "Invalid SELinux context \"{}\", expected user:role:type[:range]",
+            val
+        )),
+        false => Ok(val),
     }
+}
 
-    if path.components().any(|c| c == Component::ParentDir) {
-        return Err(anyhow!("{} {:#?} is not normalized", key, path));
+fn x_systemd_options(options: &str) -> impl Iterator<Item = (&str, &str)> {
+    options
+        .split(',')
+        .filter_map(|opt| opt.strip_prefix("x-systemd."))
+        .map(|opt| opt.split_once('=').unwrap_or((opt, "")))
+}
+
+/// Only the `x-systemd.*` options which systemd.mount(5) turns into unit settings are supported,
+/// since the generator has to do the same
+fn verify_x_systemd_options(options: &str) -> Result<()> {
+    for (name, value) in x_systemd_options(options) {
+        match name {
+            "before" | "after" | "requires" | "wanted-by" | "required-by" => {
+                match &parse_unit_names(value)?[..] {
+                    [_] => {}
+                    _ => return Err(anyhow!("x-systemd.{} takes one unit name", name)),
+                }
+            }
+            "mount-timeout" => {
+                parse_timespan(value).with_context(|| format!("x-systemd.{}", name))?;
+            }
+            _ => return Err(anyhow!("unsupported mount option x-systemd.{}", name)),
+        }
     }
+    Ok(())
+}
 
-    Ok(path.components().collect()) // normalise away /./ components
+/// A whitespace-separated list of unit names, like "local-fs.target builder.service"
+fn parse_unit_names(val: &str) -> Result<Vec<String>> {
+    val.split_whitespace()
+        .map(|unit| match unit.rsplit_once('.') {
+            Some((name, suffix))
+                if !name.is_empty() && !suffix.is_empty() && !unit.contains(['/', '"', '%']) =>
+            {
+                Ok(unit.to_string())
+            }
+            _ => Err(anyhow!("Invalid unit name \"{}\"", unit)),
+        })
+        .collect()
 }
 
 fn parse_line(dev: &mut Device, key: &str, value: &str) -> Result<()> {
@@ -325,25 +2012,126 @@ fn parse_line(dev: &mut Device, key: &str, value: &str) -> Result<()> {
             dev.host_memory_limit_mb = parse_optional_size(value)?;
         }
 
+        "host-memory-minimum" => {
+            dev.host_memory_minimum_mb = parse_optional_size(value)?;
+        }
+
+        "condition-memory" => {
+            dev.condition_memory = match value {
+                "" => None,
+                _ => Some(
+                    parse_memory_condition(value)
+                        .with_context(|| format!("{} {}", dev.name, key))?,
+                ),
+            };
+        }
+
+        "condition-virtualization" => {
+            dev.condition_virtualization = match value {
+                "" => None,
+                _ => Some(parse_negatable(value)),
+            };
+        }
+
+        "condition-kernel-command-line" => {
+            dev.condition_kernel_command_line =
+                value.split_whitespace().map(parse_negatable).collect();
+        }
+
+        "condition-architecture" => {
+            dev.condition_architecture = match value {
+                "" => None,
+                _ => Some(parse_negatable(value)),
+            };
+        }
+
+        "condition-path-exists" => {
+            dev.condition_path_exists = match value {
+                "" => None,
+                _ => {
+                    let cond = parse_negatable(value);
+                    if !cond.value.starts_with('/') {
+                        return Err(anyhow!(
+                            "{}: {}={} is not an absolute path",
+                            dev.name,
+                            key,
+                            value
+                        ));
+                    }
+                    Some(Negatable {
+                        negate: cond.negate,
+                        value: PathBuf::from(cond.value),
+                    })
+                }
+            };
+        }
+
         "zram-size" => {
-            let mut sl = fasteval::Slab::new();
-            dev.zram_size = Some((
-                value.to_string(),
-                fasteval::Parser::new()
-                    .parse_noclear(value, &mut sl.ps)
-                    .with_context(|| format!("{} zram-size", dev.name))?,
-                sl,
-            ));
+            dev.zram_size = Some(parse_size_expression(&dev.name, key, value)?);
+        }
+
+        "mem-limit" => {
+            dev.mem_limit = match value {
+                "none" => None,
+                _ => Some(parse_size_expression(&dev.name, key, value)?),
+            };
         }
 
         "compression-algorithm" => {
-            dev.compression_algorithm = Some(value.to_string());
+            dev.compression_algorithms = parse_compression_algorithms(value)?;
+        }
+
+        "recompression-algorithm" => {
+            dev.recompression_algorithms = parse_recompression_algorithms(value)?;
+        }
+
+        "recompression-interval" => {
+            dev.recompression_interval = match parse_timespan(value)? {
+                0 => return Err(anyhow!("{} {} is zero", key, value)),
+                ri => Some(ri),
+            };
+        }
+
+        "memory-hotplug" => {
+            dev.memory_hotplug =
+                HotplugPolicy::parse(value).with_context(|| format!("{} {}", dev.name, key))?;
+        }
+
+        "recompression-type" => {
+            dev.recompression_type = match value {
+                "idle" | "huge" | "huge_idle" => value.to_string().into(),
+                _ => return Err(anyhow!("{} {} not idle, huge, or huge_idle", key, value)),
+            };
+        }
+
+        "recompression-threshold" => {
+            dev.recompression_threshold = Some(value.parse().with_context(|| {
+                format!("Failed to parse recompression-threshold \"{}\"", value)
+            })?);
         }
 
         "writeback-device" => {
             dev.writeback_dev = Some(verify_mount_point(key, value)?);
         }
 
+        "writeback-interval" => {
+            dev.writeback_interval = match parse_timespan(value)? {
+                0 => return Err(anyhow!("{} {} is zero", key, value)),
+                wi => Some(wi),
+            };
+        }
+
+        "writeback-idle-age" => {
+            dev.writeback_idle_age = match parse_timespan(value)? {
+                0 => return Err(anyhow!("{} {} is zero", key, value)),
+                wia => Some(wia),
+            };
+        }
+
+        "writeback-limit" => {
+            dev.writeback_limit_mb = parse_optional_size(value)?;
+        }
+
         "swap-priority" => {
             dev.swap_priority = parse_swap_priority(value)?;
         }
@@ -357,9 +2145,42 @@ fn parse_line(dev: &mut Device, key: &str, value: &str) -> Result<()> {
         }
 
         "options" => {
+            verify_x_systemd_options(value)?;
             dev.options = value.to_string().into();
         }
 
+        "root-mode" => {
+            dev.root_mode = match value {
+                "" => None,
+                _ => Some(parse_mode(value)?),
+            };
+        }
+
+        "root-owner" => {
+            dev.root_owner = match value {
+                "" => None,
+                _ => Some(verify_owner(value)?.to_string()),
+            };
+        }
+
+        "selinux-context" => {
+            dev.selinux_context = match value {
+                "" => None,
+                _ => Some(verify_selinux_context(value)?.to_string()),
+            };
+        }
+
+        "before" => {
+            dev.before = parse_unit_names(value)?;
+        }
+
+        "wanted-by" => {
+            dev.wanted_by = match value {
+                "" => None,
+                _ => Some(parse_unit_names(value)?),
+            };
+        }
+
         "zram-fraction" => {
             /* zram-fraction is for backwards compat. zram-size = is preferred. */
 
@@ -383,34 +2204,106 @@ fn parse_line(dev: &mut Device, key: &str, value: &str) -> Result<()> {
             dev.max_zram_size_mb = Some(parse_optional_size(value)?);
         }
 
-        _ => {
-            warn!("{}: unknown key {}, ignoring.", dev.name, key);
-        }
+        _ => match TUNABLES.iter().find(|tunable| tunable.key == key) {
+            Some(tunable) => match value {
+                "" => {
+                    dev.tunables.remove(tunable.key);
+                }
+                _ => {
+                    dev.tunables
+                        .insert(tunable.key, parse_tunable(tunable, value)?);
+                }
+            },
+            None => return Err(UnknownKey(key.to_string()).into()),
+        },
     }
 
     Ok(())
 }
 
-fn _get_total_memory_kb(path: &Path) -> Result<u64> {
+/// Parses "Field: value [kB]" lines, as found in /proc/meminfo and the per-node meminfo files
+/// (where each line is prefixed with "Node N")
+fn read_meminfo(path: &Path) -> Result<HashMap<String, u64>> {
+    let mut ret = HashMap::new();
     for line in
-        BufReader::new(fs::File::open(&path).with_context(|| {
+        BufReader::new(fs::File::open(path).with_context(|| {
             format!("Failed to read memory information from {}", path.display())
         })?)
         .lines()
     {
         let line = line?;
-        let mut fields = line.split_whitespace();
-        if let (Some("MemTotal:"), Some(val)) = (fields.next(), fields.next()) {
-            return Ok(val.parse()?);
+        let mut fields = line.split_whitespace().skip_while(|f| !f.ends_with(':'));
+        if let (Some(key), Some(val)) = (fields.next(), fields.next()) {
+            ret.insert(
+                key[..key.len() - 1].to_string(),
+                val.parse()
+                    .with_context(|| format!("{}: invalid {} {}", path.display(), key, val))?,
+            );
         }
     }
 
-    Err(anyhow!("Couldn't find MemTotal in {}", path.display()))
+    Ok(ret)
+}
+
+/// `meminfo` as read from `path` by `read_meminfo()`
+fn get_total_memory_kb(meminfo: &HashMap<String, u64>, path: &Path) -> Result<u64> {
+    meminfo
+        .get("MemTotal")
+        .copied()
+        .ok_or_else(|| anyhow!("Couldn't find MemTotal in {}", path.display()))
+}
+
+/// Counts the CPUs in a list like "0-3,8-11"
+fn parse_cpu_list(list: &str) -> Result<u64> {
+    list.trim()
+        .split(',')
+        .filter(|r| !r.is_empty())
+        .map(|r| match r.split_once('-') {
+            Some((from, to)) => Ok(to.parse::<u64>()?.saturating_sub(from.parse()?) + 1),
+            None => r.parse::<u64>().map(|_| 1).map_err(Into::into),
+        })
+        .sum::<Result<u64>>()
+        .with_context(|| format!("Invalid CPU list \"{}\"", list.trim()))
+}
+
+fn read_online_cpus(root: &Path) -> Option<u64> {
+    let path = root.join("sys/devices/system/cpu/online");
+    match fs::read_to_string(&path)
+        .map_err(Into::into)
+        .and_then(|l| parse_cpu_list(&l))
+    {
+        Ok(cpus) => Some(cpus),
+        Err(e) => {
+            debug!("{}: {:#}", path.display(), e);
+            None
+        }
+    }
 }
 
-fn get_total_memory_kb(root: &Path) -> Result<u64> {
-    let path = root.join("proc/meminfo");
-    _get_total_memory_kb(&path)
+fn read_node_memory(root: &Path) -> Option<BTreeMap<u64, f64>> {
+    let path = root.join("sys/devices/system/node");
+    let mut ret = BTreeMap::new();
+    for entry in fs::read_dir(&path).ok()?.flatten() {
+        let node = match entry
+            .file_name()
+            .to_str()
+            .and_then(|n| n.strip_prefix("node"))
+            .and_then(|n| n.parse::<u64>().ok())
+        {
+            Some(node) => node,
+            None => continue,
+        };
+
+        match read_meminfo(&entry.path().join("meminfo")).map(|mi| mi.get("MemTotal").copied()) {
+            Ok(Some(kb)) => {
+                ret.insert(node, kb as f64 / 1024.);
+            }
+            Ok(None) => debug!("{}: no MemTotal", entry.path().display()),
+            Err(e) => debug!("{:#}", e),
+        }
+    }
+
+    Some(ret)
 }
 
 fn _kernel_has_option(path: &Path, word: &str) -> Result<Option<bool>> {
@@ -434,6 +2327,79 @@ pub fn kernel_has_option(root: &Path, word: &str) -> Result<Option<bool>> {
     _kernel_has_option(&path, word)
 }
 
+/// Like systemd.unit(5) ConditionKernelCommandLine=:
+/// "foo=bar" must appear verbatim, "foo" matches "foo" and "foo=" with any value
+fn kernel_command_line_matches(cmdline: &str, cond: &str) -> bool {
+    cmdline.split_whitespace().any(|w| {
+        w == cond
+            || (!cond.contains('=')
+                && matches!(w.strip_prefix(cond), Some(rest) if rest.starts_with('=')))
+    })
+}
+
+/// As reported by systemd-detect-virt(1), None if not virtualized or detection failed
+fn detect_virtualization() -> Option<String> {
+    match Command::new("systemd-detect-virt").output() {
+        Ok(output) => parse_detect_virt(&String::from_utf8_lossy(&output.stdout)),
+        Err(e) => {
+            warn!(
+                "systemd-detect-virt call failed, assuming no virtualization: {}",
+                e
+            );
+            None
+        }
+    }
+}
+
+fn parse_detect_virt(output: &str) -> Option<String> {
+    match output.trim() {
+        "" | "none" => None,
+        virt => Some(virt.to_string()),
+    }
+}
+
+/// Identifiers systemd-detect-virt(1) may print for containers, all others are VMs
+const CONTAINER_VIRTUALIZATIONS: &[&str] = &[
+    "openvz",
+    "lxc",
+    "lxc-libvirt",
+    "systemd-nspawn",
+    "docker",
+    "podman",
+    "rkt",
+    "wsl",
+    "proot",
+    "pouch",
+];
+
+fn virtualization_matches(detected: Option<&str>, condition: &str) -> bool {
+    let is_container = |virt: &str| CONTAINER_VIRTUALIZATIONS.contains(&virt);
+    match condition {
+        "yes" => detected.is_some(),
+        "no" => detected.is_none(),
+        "vm" => matches!(detected, Some(virt) if !is_container(virt)),
+        "container" => matches!(detected, Some(virt) if is_container(virt)),
+        id => detected == Some(id),
+    }
+}
+
+/// Names as in systemd.unit(5) ConditionArchitecture=
+fn native_architecture() -> &'static str {
+    let little_endian = cfg!(target_endian = "little");
+    match std::env::consts::ARCH {
+        "x86_64" => "x86-64",
+        "aarch64" if little_endian => "arm64",
+        "aarch64" => "arm64-be",
+        "arm" if !little_endian => "arm-be",
+        "powerpc64" if little_endian => "ppc64-le",
+        "powerpc64" => "ppc64",
+        "powerpc" => "ppc",
+        "mips64" if little_endian => "mips64-le",
+        "mips" if little_endian => "mips-le",
+        arch => arch,
+    }
+}
+
 pub fn kernel_zram_option(root: &Path) -> Option<bool> {
     match kernel_has_option(root, "systemd.zram") {
         Ok(r @ Some(true)) | Ok(r @ None) => r,
@@ -454,7 +2420,7 @@ mod tests {
 
     fn file_with(data: &[u8]) -> tempfile::NamedTempFile {
         let mut file = tempfile::NamedTempFile::new().unwrap();
-        file.write(data).unwrap();
+        file.write_all(data).unwrap();
         file.flush().unwrap();
         file
     }
@@ -469,7 +2435,8 @@ MemAvailable:    1740336 kB
 Buffers:          292752 kB
 ",
         );
-        let mem = _get_total_memory_kb(file.path()).unwrap();
+        let meminfo = read_meminfo(file.path()).unwrap();
+        let mem = get_total_memory_kb(&meminfo, file.path()).unwrap();
         assert_eq!(mem, 8013220);
     }
 
@@ -483,7 +2450,8 @@ aMemTotal:        8013220 kB
 MemTotal::        8013220 kB
 ",
         );
-        _get_total_memory_kb(file.path()).unwrap();
+        let meminfo = read_meminfo(file.path()).unwrap();
+        get_total_memory_kb(&meminfo, file.path()).unwrap();
     }
 
     #[test]
@@ -492,6 +2460,19 @@ MemTotal::        8013220 kB
         assert_eq!(_kernel_has_option(file.path(), "foo").unwrap(), Some(true));
     }
 
+    #[test]
+    fn test_kernel_command_line_matches() {
+        let cmdline = "quiet foo=0 bar=1 baz.x=y\n";
+        assert!(kernel_command_line_matches(cmdline, "quiet"));
+        assert!(kernel_command_line_matches(cmdline, "foo"));
+        assert!(kernel_command_line_matches(cmdline, "foo=0"));
+        assert!(!kernel_command_line_matches(cmdline, "foo=1"));
+        assert!(kernel_command_line_matches(cmdline, "baz.x"));
+        assert!(!kernel_command_line_matches(cmdline, "baz"));
+        assert!(!kernel_command_line_matches(cmdline, "ba"));
+        assert!(!kernel_command_line_matches(cmdline, "quiet=1"));
+    }
+
     #[test]
     fn test_kernel_has_no_option() {
         let file = file_with(
@@ -526,13 +2507,455 @@ foo=0
         }
     }
 
+    #[test]
+    fn test_parse_mode() {
+        assert_eq!(parse_mode("1777").unwrap(), 0o1777);
+        assert_eq!(parse_mode("755").unwrap(), 0o755);
+        assert_eq!(parse_mode("0").unwrap(), 0);
+        for e in ["", "u+rwx", "888", "17777", "-1"] {
+            assert!(parse_mode(e).is_err(), "{}", e);
+        }
+    }
+
+    #[test]
+    fn test_verify_owner() {
+        for o in [
+            "root",
+            "1000",
+            "user:group",
+            "nobody:65534",
+            ":wheel",
+            "svc_user-1",
+        ] {
+            assert_eq!(verify_owner(o).unwrap(), o);
+        }
+        for e in ["", ":", "user:", "a:b:c", "user name", "user,group"] {
+            assert!(verify_owner(e).is_err(), "{}", e);
+        }
+    }
+
+    #[test]
+    fn test_mount_options() {
+        let mut dev = Device::new("zram0".to_string());
+        assert_eq!(dev.mount_options(), "discard");
+        dev.selinux_context = Some(
+            verify_selinux_context("system_u:object_r:tmp_t:s0:c0,c1")
+                .unwrap()
+                .to_string(),
+        );
+        assert_eq!(
+            dev.mount_options(),
+            "discard,context=\"system_u:object_r:tmp_t:s0:c0,c1\""
+        );
+        dev.options = "".into();
+        assert_eq!(
+            dev.mount_options(),
+            "context=\"system_u:object_r:tmp_t:s0:c0,c1\""
+        );
+        for e in ["tmp_t", "a:b:\"c\"", "a:b: c"] {
+            assert!(verify_selinux_context(e).is_err(), "{}", e);
+        }
+    }
+
+    #[test]
+    fn test_parse_unit_names() {
+        assert_eq!(
+            parse_unit_names(" initrd-fs.target  builder.service ").unwrap(),
+            ["initrd-fs.target", "builder.service"]
+        );
+        assert!(parse_unit_names("").unwrap().is_empty());
+        for e in [
+            "builder",
+            ".target",
+            "local-fs.",
+            "/etc/foo.service",
+            "foo%i.service",
+        ] {
+            assert!(parse_unit_names(e).is_err(), "{}", e);
+        }
+    }
+
+    #[test]
+    fn test_verify_x_systemd_options() {
+        for o in [
+            "",
+            "discard",
+            "discard,x-systemd.before=builder.service",
+            "x-systemd.after=a.mount,x-systemd.requires=b.service,noatime",
+            "x-systemd.wanted-by=initrd-fs.target,x-systemd.required-by=c.target",
+            "x-systemd.mount-timeout=1min 30s",
+        ] {
+            verify_x_systemd_options(o).unwrap();
+        }
+        for e in [
+            "x-systemd.before=",
+            "x-systemd.before=a.service b.service",
+            "x-systemd.after=builder",
+            "x-systemd.mount-timeout=soon",
+            "x-systemd.automount",
+            "discard,x-systemd.device-timeout=5s",
+        ] {
+            assert!(verify_x_systemd_options(e).is_err(), "{}", e);
+        }
+
+        let mut dev = Device::new("zram0".to_string());
+        dev.options = "discard,x-systemd.before=a.service,x-systemd.automount".into();
+        assert_eq!(
+            dev.x_systemd_options().collect::<Vec<_>>(),
+            [("before", "a.service"), ("automount", "")]
+        );
+    }
+
+    #[test]
+    fn test_read_meminfo_node() {
+        let file = file_with(
+            b"\
+Node 1 MemTotal:        4096000 kB
+Node 1 MemFree:          721288 kB
+Node 1 HugePages_Total:     0
+",
+        );
+        let mi = read_meminfo(file.path()).unwrap();
+        assert_eq!(mi.get("MemTotal"), Some(&4096000));
+        assert_eq!(mi.get("HugePages_Total"), Some(&0));
+    }
+
+    #[test]
+    fn test_parse_cpu_list() {
+        assert_eq!(parse_cpu_list("0\n").unwrap(), 1);
+        assert_eq!(parse_cpu_list("0-3,8-11\n").unwrap(), 8);
+        assert_eq!(parse_cpu_list("0,2,4-5").unwrap(), 4);
+        assert!(parse_cpu_list("0-a").is_err());
+    }
+
+    #[test]
+    fn test_selector() {
+        let parse = |section| Selector::parse(section).unwrap();
+        assert_eq!(parse("zram0"), Some(Selector::One(0)));
+        assert_eq!(parse("zram12"), Some(Selector::One(12)));
+        assert_eq!(parse("zram0-3"), Some(Selector::Range(0, 3)));
+        assert_eq!(parse("zram2-2"), Some(Selector::Range(2, 2)));
+        assert_eq!(parse("zram*"), Some(Selector::All));
+        assert_eq!(parse("zram-1"), None);
+        assert_eq!(parse("zram1-"), None);
+        assert_eq!(parse("zram"), None);
+        assert_eq!(parse("zramx"), None);
+        assert_eq!(parse("swap"), None);
+        assert_eq!(parse("zram-generator"), Some(Selector::Defaults));
+        assert_eq!(
+            Selector::parse("zram3-1").unwrap_err().to_string(),
+            "invalid range \"zram3-1\": zram3 comes after zram1"
+        );
+        assert_eq!(parse("zram0-255"), Some(Selector::Range(0, 255)));
+        assert_eq!(
+            Selector::parse("zram0-256").unwrap_err().to_string(),
+            "invalid range \"zram0-256\": more than 256 devices"
+        );
+        assert!(Selector::parse("zram0-4294967295").is_err());
+        assert!(Selector::parse("zram0-18446744073709551615").is_err());
+
+        assert!(Selector::Range(1, 3).matches(1));
+        assert!(Selector::Range(1, 3).matches(3));
+        assert!(!Selector::Range(1, 3).matches(4));
+        assert!(!Selector::One(1).matches(2));
+        assert!(Selector::All.matches(7));
+        assert!(Selector::Defaults.matches(7));
+        assert!(Selector::Defaults.precedence() < Selector::All.precedence());
+    }
+
+    #[test]
+    fn test_parse_memory_condition() {
+        assert_eq!(
+            parse_memory_condition("2048").unwrap(),
+            (Comparison::GreaterEqual, 2048)
+        );
+        assert_eq!(
+            parse_memory_condition("< 2048").unwrap(),
+            (Comparison::Less, 2048)
+        );
+        assert_eq!(
+            parse_memory_condition("<=2048").unwrap(),
+            (Comparison::LessEqual, 2048)
+        );
+        assert_eq!(
+            parse_memory_condition("!=0").unwrap(),
+            (Comparison::NotEqual, 0)
+        );
+        assert_eq!(
+            parse_memory_condition("=1").unwrap(),
+            (Comparison::Equal, 1)
+        );
+        assert!(parse_memory_condition("=>1").is_err());
+        assert!(parse_memory_condition(">=").is_err());
+        assert_eq!(
+            parse_memory_condition("<= 4G").unwrap(),
+            (Comparison::LessEqual, 4096)
+        );
+        assert_eq!(Comparison::LessEqual.to_string(), "<=");
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("2048").unwrap(), 2048);
+        assert_eq!(parse_size("8G").unwrap(), 8192);
+        assert_eq!(parse_size("8 GiB").unwrap(), 8192);
+        assert_eq!(parse_size("512M").unwrap(), 512);
+        assert_eq!(parse_size("1.5G").unwrap(), 1536);
+        assert_eq!(parse_size("2048K").unwrap(), 2);
+        assert_eq!(parse_size("512K").unwrap(), 1);
+        assert_eq!(parse_size("1025K").unwrap(), 2);
+        assert_eq!(parse_size("0.1").unwrap(), 1);
+        assert_eq!(parse_size("0").unwrap(), 0);
+        assert_eq!(parse_size("1T").unwrap(), 1024 * 1024);
+        assert_eq!(parse_size("4 * 1024").unwrap(), 4096);
+        assert_eq!(parse_optional_size("8G").unwrap(), Some(8192));
+        assert_eq!(parse_optional_size("none").unwrap(), None);
+
+        for ambiguous in ["8GB", "8g", "8 gb", "512MB", "1kB", "8B"] {
+            let e = parse_size(ambiguous).unwrap_err();
+            assert!(
+                e.to_string().contains("ambiguous unit"),
+                "{}: {}",
+                ambiguous,
+                e
+            );
+        }
+        for key in [
+            "host-memory-limit",
+            "host-memory-minimum",
+            "max-zram-size",
+            "writeback-limit",
+            "condition-memory",
+        ] {
+            let mut dev = Device::new("zram0".to_string());
+            let e = parse_line(&mut dev, key, "75%").unwrap_err();
+            assert!(
+                format!("{:#}", e)
+                    .contains("is a percentage, which only zram-size and mem-limit accept"),
+                "{}: {:#}",
+                key,
+                e
+            );
+        }
+        assert!(parse_size("ram / 2").is_err());
+        assert!(parse_size("-1G").is_err());
+        assert!(parse_size("2 * 4G").is_err());
+        assert_eq!(parse_size("1e3").unwrap(), 1000);
+        assert!(parse_size("").is_err());
+    }
+
+    #[test]
+    fn test_parse_size_expression() {
+        let mut ram = |name: &str, _: Vec<f64>| (name == "ram").then_some(8192.);
+        for (val, mib) in [
+            ("ram / 2", 4096.),
+            ("4G", 4096.),
+            ("75%", 6144.),
+            ("12.5 %", 1024.),
+        ] {
+            let (text, expr, slab) = parse_size_expression("zram0", "zram-size", val).unwrap();
+            assert_eq!(text, val);
+            assert_eq!(expr.from(&slab.ps).eval(&slab, &mut ram).unwrap(), mib);
+        }
+        assert!(parse_size_expression("zram0", "zram-size", "4GB").is_err());
+        // Deprecated, only reported by --verify
+        let (_, expr, slab) = parse_size_expression("zram0", "zram-size", "ram / 2 + 1G").unwrap();
+        assert_eq!(
+            expr.from(&slab.ps).eval(&slab, &mut ram).unwrap(),
+            4096. + 1e9
+        );
+        assert!(check_expression_units("ram / 2 + 1G").is_err());
+        assert!(check_expression_units("ram / 2 + 1e3").is_ok());
+    }
+
+    #[test]
+    fn test_virtualization_matches() {
+        assert!(virtualization_matches(None, "no"));
+        assert!(!virtualization_matches(None, "yes"));
+        assert!(!virtualization_matches(None, "vm"));
+        assert!(virtualization_matches(Some("kvm"), "yes"));
+        assert!(virtualization_matches(Some("kvm"), "vm"));
+        assert!(virtualization_matches(Some("kvm"), "kvm"));
+        assert!(!virtualization_matches(Some("kvm"), "container"));
+        assert!(!virtualization_matches(Some("kvm"), "qemu"));
+        assert!(virtualization_matches(Some("podman"), "container"));
+        assert!(!virtualization_matches(Some("podman"), "vm"));
+
+        assert_eq!(parse_detect_virt("none\n"), None);
+        assert_eq!(parse_detect_virt(""), None);
+        assert_eq!(parse_detect_virt("kvm\n").as_deref(), Some("kvm"));
+        assert!(Virtualization::fake("docker\n").is_container());
+        assert!(!Virtualization::fake("kvm").is_container());
+    }
+
+    #[test]
+    fn test_locate_lines() {
+        let lines = locate_lines(
+            "\
+# comment = yes
+[zram0]
+zram-size = ram
+; options = no
+options=
+[ zram1 ]
+zram-size: ram / 2
+[zram0]
+zram-size = 100
+",
+        );
+        let get = |s: &str, k: &str| lines.keys.get(&(s.to_string(), k.to_string())).cloned();
+        assert_eq!(get("zram0", "zram-size"), Some(vec![3, 9]));
+        assert_eq!(get("zram0", "options"), Some(vec![5]));
+        assert_eq!(get("zram1", "zram-size"), Some(vec![7]));
+        assert_eq!(get("zram0", "comment"), None);
+        assert_eq!(lines.keys.len(), 3);
+        assert_eq!(lines.sections["zram0"], [2, 8]);
+        assert_eq!(lines.sections["zram1"], [6]);
+        assert!(lines.general.is_empty());
+    }
+
+    #[test]
+    fn test_parse_timespan() {
+        for (s, v) in [
+            ("3600", 3600),
+            ("1h", 3600),
+            ("1h 30min", 90 * 60),
+            ("1h30m", 90 * 60),
+            ("2 days", 2 * 24 * 60 * 60),
+            ("1w 1s", 7 * 24 * 60 * 60 + 1),
+        ] {
+            assert_eq!(parse_timespan(s).unwrap(), v, "{}", s);
+        }
+
+        for s in ["", " ", "1 fortnight", "h", "1.5h", "-1s"] {
+            assert!(parse_timespan(s).is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn test_parse_algorithms() {
+        let algs = parse_algorithms(" zstd  lz4hc( priority=2 , level = 9 )\tdeflate() ").unwrap();
+        assert_eq!(
+            algs,
+            [
+                Algorithm {
+                    name: "zstd".to_string(),
+                    params: vec![],
+                },
+                Algorithm {
+                    name: "lz4hc".to_string(),
+                    params: vec![
+                        ("priority".to_string(), "2".to_string()),
+                        ("level".to_string(), "9".to_string()),
+                    ],
+                },
+                Algorithm {
+                    name: "deflate".to_string(),
+                    params: vec![],
+                },
+            ]
+        );
+        assert_eq!(algs[1].to_string(), "lz4hc(priority=2,level=9)");
+        assert_eq!(algs[1].param("level"), Some("9"));
+        assert!(parse_algorithms("").unwrap().is_empty());
+
+        for s in [
+            "zstd(",
+            "zstd)",
+            "zstd(level)",
+            "(level=1)",
+            "zstd(level=1)x",
+        ] {
+            assert!(parse_algorithms(s).is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn test_parse_recompression_algorithms() {
+        assert!(parse_recompression_algorithms("zstd deflate(priority=3)").is_ok());
+        assert!(parse_recompression_algorithms("zstd(priority=0)").is_err());
+        assert!(parse_recompression_algorithms("zstd(priority=4)").is_err());
+        assert!(parse_recompression_algorithms("zstd(level=4)").is_ok());
+        assert!(parse_recompression_algorithms("zstd(speed=4)").is_err());
+        assert!(parse_recompression_algorithms("a b c d").is_err());
+
+        assert!(parse_recompression_algorithms("zstd(priority=2) lz4(priority=1)").is_ok());
+        assert!(parse_recompression_algorithms("zstd(priority=2) lz4").is_err());
+        let e = parse_recompression_algorithms("zstd(priority=3) lz4(priority=3)").unwrap_err();
+        assert_eq!(e.to_string(), "lz4: priority 3 already used by zstd");
+    }
+
+    #[test]
+    fn test_parse_sysctl_name() {
+        assert_eq!(parse_sysctl_name("vm.swappiness").unwrap(), "vm.swappiness");
+        assert_eq!(
+            parse_sysctl_name("vm/page-cluster").unwrap(),
+            "vm.page-cluster"
+        );
+        assert!(parse_sysctl_name("kernel.panic").is_err());
+        assert!(parse_sysctl_name("vm.").is_err());
+        assert!(parse_sysctl_name("vm.Swappiness").is_err());
+        assert!(parse_sysctl_name("vm.swappiness = 1").is_err());
+    }
+
+    #[test]
+    fn test_parse_tunables() {
+        let mut dev = Device::new("zram0".to_string());
+        parse_line(&mut dev, "read-ahead-kb", "0").unwrap();
+        parse_line(&mut dev, "max-comp-streams", "4").unwrap();
+        assert_eq!(dev.tunables.get("read-ahead-kb"), Some(&0));
+        assert_eq!(dev.tunables.get("max-comp-streams"), Some(&4));
+
+        parse_line(&mut dev, "read-ahead-kb", "").unwrap();
+        assert_eq!(dev.tunables.get("read-ahead-kb"), None);
+
+        assert!(parse_line(&mut dev, "max-comp-streams", "0").is_err());
+        assert!(parse_line(&mut dev, "nr-requests", "-1").is_err());
+        assert!(parse_line(&mut dev, "nr-requests", "many").is_err());
+        assert!(parse_line(&mut dev, "queue/nr_requests", "64")
+            .unwrap_err()
+            .is::<UnknownKey>());
+    }
+
+    #[test]
+    fn test_parse_compression_algorithms() {
+        let algs = parse_compression_algorithms("zstd(level=-3, dict=/etc/zram.dict) lz4 lzo-rle")
+            .unwrap();
+        assert_eq!(algs.len(), 3);
+        assert_eq!(algs[0].name, "zstd");
+        assert_eq!(algs[0].param("level"), Some("-3"));
+        assert_eq!(algs[0].param("dict"), Some("/etc/zram.dict"));
+        assert_eq!(algs[2].to_string(), "lzo-rle");
+        assert!(parse_compression_algorithms("").unwrap().is_empty());
+
+        for s in [
+            "zstd(level=high)",
+            "lz4 zstd(dict=zram.dict)",
+            "zstd(priority=1)",
+            "zstd(",
+        ] {
+            assert!(parse_compression_algorithms(s).is_err(), "{}", s);
+        }
+    }
+
     fn dev_with_zram_size_size(val: Option<&str>, memtotal_mb: u64) -> u64 {
         let mut dev = Device::new("zram0".to_string());
         if let Some(val) = val {
             parse_line(&mut dev, "zram-size", val).unwrap();
         }
-        assert!(dev.is_enabled(memtotal_mb));
-        dev.set_disksize_if_enabled(memtotal_mb).unwrap();
+        let host = HostInfo {
+            memtotal_mb,
+            ..Default::default()
+        };
+        assert!(dev.is_enabled(&host));
+        dev.set_disksize_if_enabled(
+            &HostInfo {
+                memtotal_mb,
+                ..Default::default()
+            },
+            1,
+        )
+        .unwrap();
         dev.disksize
     }
 
@@ -579,6 +3002,49 @@ foo=0
         assert_eq!(dev_with_zram_size_size(Some("(ram-99)/0"), 100), u64::MAX); // +∞
     }
 
+    #[test]
+    fn test_eval_mem_limit() {
+        let mut dev = Device::new("zram0".to_string());
+        dev.set_disksize_if_enabled(
+            &HostInfo {
+                memtotal_mb: 100,
+                ..Default::default()
+            },
+            1,
+        )
+        .unwrap();
+        assert_eq!(dev.mem_limit_bytes, 0);
+
+        parse_line(&mut dev, "mem-limit", "ram / 4").unwrap();
+        dev.set_disksize_if_enabled(
+            &HostInfo {
+                memtotal_mb: 100,
+                ..Default::default()
+            },
+            1,
+        )
+        .unwrap();
+        assert_eq!(dev.mem_limit_bytes, 25 * 1024 * 1024);
+
+        parse_line(&mut dev, "mem-limit", "none").unwrap();
+        assert!(dev.mem_limit.is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "mem-limit=-50 < 0")]
+    fn test_eval_mem_limit_negative() {
+        let mut dev = Device::new("zram0".to_string());
+        parse_line(&mut dev, "mem-limit", "ram / 2 - 100").unwrap();
+        dev.set_disksize_if_enabled(
+            &HostInfo {
+                memtotal_mb: 100,
+                ..Default::default()
+            },
+            1,
+        )
+        .unwrap();
+    }
+
     #[test]
     fn test_eval_size_expression_min() {
         assert_eq!(
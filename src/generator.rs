@@ -1,6 +1,7 @@
 /* SPDX-License-Identifier: MIT */
 
-use crate::config::Device;
+use crate::config::{Config, Device, HotplugPolicy};
+use crate::setup;
 use anyhow::{anyhow, Context, Result};
 use log::{debug, log, warn, Level};
 use std::cmp;
@@ -15,7 +16,7 @@ fn make_parent(of: &Path) -> Result<()> {
     let parent = of
         .parent()
         .ok_or_else(|| anyhow!("Couldn't get parent of {}", of.display()))?;
-    fs::create_dir_all(&parent)?;
+    fs::create_dir_all(parent)?;
     Ok(())
 }
 
@@ -26,29 +27,7 @@ fn make_symlink(dst: &str, src: &Path) -> Result<()> {
     Ok(())
 }
 
-fn virtualization_container() -> Result<bool> {
-    let mut child = match Command::new("systemd-detect-virt")
-        .arg("--quiet")
-        .arg("--container")
-        .spawn()
-    {
-        Ok(child) => child,
-        Err(e) => {
-            warn!(
-                "systemd-detect-virt call failed, assuming we're not in a container: {}",
-                e
-            );
-            return Ok(false);
-        }
-    };
-
-    match child.wait() {
-        Ok(status) => Ok(status.success()),
-        Err(e) => Err(anyhow!("systemd-detect-virt call failed: {}", e)),
-    }
-}
-
-fn modprobe(modname: &str, required: bool) {
+pub(crate) fn modprobe(modname: &str, required: bool) {
     match Command::new("modprobe").arg(modname).status() {
         Err(e) => {
             let level = match !required && e.kind() == io::ErrorKind::NotFound {
@@ -71,13 +50,16 @@ fn modprobe(modname: &str, required: bool) {
     };
 }
 
-pub fn run_generator(devices: &[Device], output_directory: &Path, fake_mode: bool) -> Result<()> {
+/// Write the units for the configured devices into `output_directory`, then, unless in `fake_mode`, create the devices
+pub fn run_generator(config: &Config, output_directory: &Path, fake_mode: bool) -> Result<()> {
+    let devices = &config.devices[..];
+
     if devices.is_empty() {
         debug!("No devices configured, exiting.");
         return Ok(());
     }
 
-    if virtualization_container()? && !fake_mode {
+    if !fake_mode && config.virtualization.is_container() {
         debug!("Running in a container, exiting.");
         return Ok(());
     }
@@ -85,39 +67,57 @@ pub fn run_generator(devices: &[Device], output_directory: &Path, fake_mode: boo
     for device in devices {
         handle_device(output_directory, device)?;
     }
+    handle_memory_hotplug(output_directory, devices)?;
+    handle_sysctl(output_directory, config)?;
 
     if !devices.is_empty() && !fake_mode {
         /* We created some units, let's make sure the module is loaded and the devices exist */
-        if !Path::new("/sys/class/zram-control").exists() {
-            modprobe("zram", true);
-        }
+        create_devices(devices)?;
+    }
 
-        let max_device = devices
-            .iter()
-            .map(|device| {
-                device.name[4..]
-                    .parse()
-                    .expect("already verified in read_devices()")
-            })
-            .fold(0, cmp::max);
-
-        if !Path::new("/dev")
-            .join(format!("zram{}", max_device))
-            .exists()
-        {
-            while fs::read_to_string("/sys/class/zram-control/hot_add")
-                .context("Adding zram device")?
-                .trim_end()
-                .parse::<u64>()
-                .context("Fresh zram device number")?
-                < max_device
-            {}
-        }
+    load_compressors(devices);
+
+    Ok(())
+}
+
+/// Load the zram module, if needed, and hot-add devices until all of `devices` exist
+pub(crate) fn create_devices(devices: &[Device]) -> Result<()> {
+    if !Path::new("/sys/class/zram-control").exists() {
+        modprobe("zram", true);
     }
 
+    let max_device = devices
+        .iter()
+        .map(|device| {
+            device.name[4..]
+                .parse()
+                .expect("already verified in read_devices()")
+        })
+        .fold(0, cmp::max);
+
+    if !Path::new("/dev")
+        .join(format!("zram{}", max_device))
+        .exists()
+    {
+        while setup::hot_add()? < max_device {}
+    }
+
+    Ok(())
+}
+
+/// Load the modules of (re)compression algorithms the kernel doesn't know yet;
+/// fallbacks for the primary algorithm are only loaded by setup, if needed
+pub(crate) fn load_compressors(devices: &[Device]) {
     let compressors: BTreeSet<_> = devices
         .iter()
-        .flat_map(|device| device.compression_algorithm.as_deref())
+        .flat_map(|device| {
+            device
+                .compression_algorithms
+                .first()
+                .into_iter()
+                .chain(&device.recompression_algorithms)
+                .map(|alg| &alg.name[..])
+        })
         .collect();
 
     if !compressors.is_empty() {
@@ -131,8 +131,6 @@ pub fn run_generator(devices: &[Device], output_directory: &Path, fake_mode: boo
             modprobe(&format!("crypto-{}", comp), false);
         }
     }
-
-    Ok(())
 }
 
 // Returns a list of names of loaded compressors
@@ -140,7 +138,6 @@ fn parse_known_compressors(proc_crypto: &str) -> BTreeSet<&str> {
     // Extract algorithm names (this includes non-compression algorithms too)
     proc_crypto
         .lines()
-        .into_iter()
         .filter(|line| line.starts_with("name"))
         .map(|m| m.rsplit(':').next().unwrap().trim())
         .collect()
@@ -164,10 +161,244 @@ fn write_contents(output_directory: &Path, filename: &str, contents: &str) -> Re
 
 fn handle_device(output_directory: &Path, device: &Device) -> Result<()> {
     if device.is_swap() {
-        handle_zram_swap(output_directory, device)
+        handle_zram_swap(output_directory, device)?;
     } else {
-        handle_zram_mount_point(output_directory, device)
+        handle_zram_mount_point(output_directory, device)?;
+    }
+
+    match (
+        device.recompression_interval,
+        device.recompression_algorithms.is_empty(),
+    ) {
+        (Some(interval), false) => handle_zram_periodic(
+            output_directory,
+            device,
+            "recompress",
+            &format!("Recompress {} pages", device.recompression_type),
+            interval,
+        )?,
+        (Some(_), true) => warn!(
+            "{}: recompression-interval set without recompression-algorithm, ignoring.",
+            device.name
+        ),
+        (None, _) => {}
+    }
+
+    match (device.memory_hotplug, device.is_swap()) {
+        (HotplugPolicy::Ignore, _) => {}
+        (_, true) => handle_zram_resize(output_directory, device)?,
+        (_, false) => warn!(
+            "{}: memory-hotplug set for a file system, which can't be resized, ignoring.",
+            device.name
+        ),
+    }
+
+    match (device.writeback_interval, device.writeback_dev.as_ref()) {
+        (Some(interval), Some(_)) => handle_zram_periodic(
+            output_directory,
+            device,
+            "writeback",
+            "Write back idle and incompressible pages",
+            interval,
+        )?,
+        (Some(_), None) => warn!(
+            "{}: writeback-interval set without writeback-device, ignoring.",
+            device.name
+        ),
+        (None, _) => {}
+    }
+
+    Ok(())
+}
+
+/// A systemd-zram-hotplug.service installing the udev rules that start systemd-zram-resize@zramN.service,
+/// wanted by the setup services of the devices with a memory-hotplug policy, and stopped (removing them) with the last one
+fn handle_memory_hotplug(output_directory: &Path, devices: &[Device]) -> Result<()> {
+    let unit_name = "systemd-zram-hotplug.service";
+    let mut wanted_by = devices
+        .iter()
+        .filter(|device| device.is_swap() && device.memory_hotplug != HotplugPolicy::Ignore)
+        .peekable();
+    if wanted_by.peek().is_none() {
+        return Ok(());
+    }
+
+    debug!("Creating unit file {}", unit_name);
+    write_contents(
+        output_directory,
+        unit_name,
+        &format!(
+            "\
+[Unit]
+Description=Resize zram devices after memory hotplug
+Documentation=man:zram-generator(8) man:zram-generator.conf(5)
+DefaultDependencies=false
+StopWhenUnneeded=yes
+
+[Service]
+Type=oneshot
+RemainAfterExit=yes
+ExecStart={exe_name} --install-hotplug-rules
+ExecStop={exe_name} --remove-hotplug-rules
+",
+            exe_name = std::env::current_exe().unwrap().display(),
+        ),
+    )?;
+
+    for device in wanted_by {
+        let symlink_path = output_directory
+            .join(format!("systemd-zram-setup@{}.service.wants", device.name))
+            .join(unit_name);
+        make_symlink(&format!("../{}", unit_name), &symlink_path)?;
+    }
+
+    Ok(())
+}
+
+/// A systemd-zram-sysctl.service applying the `[sysctl]` settings through a sysctl.d(5) fragment,
+/// wanted by the setup services of the swap devices, and stopped (removing it) with the last one
+fn handle_sysctl(output_directory: &Path, config: &Config) -> Result<()> {
+    let unit_name = "systemd-zram-sysctl.service";
+    let mut wanted_by = config
+        .devices
+        .iter()
+        .filter(|device| device.is_swap())
+        .peekable();
+    if config.sysctl.is_empty() || wanted_by.peek().is_none() {
+        return Ok(());
+    }
+
+    debug!("Creating unit file {}", unit_name);
+    write_contents(
+        output_directory,
+        unit_name,
+        &format!(
+            "\
+[Unit]
+Description=Apply VM settings for zram swap
+Documentation=man:zram-generator(8) man:zram-generator.conf(5)
+DefaultDependencies=false
+After=systemd-sysctl.service
+StopWhenUnneeded=yes
+
+[Service]
+Type=oneshot
+RemainAfterExit=yes
+ExecStart={exe_name} --install-sysctl
+ExecStop={exe_name} --remove-sysctl
+",
+            exe_name = std::env::current_exe().unwrap().display(),
+        ),
+    )?;
+
+    for device in wanted_by {
+        let symlink_path = output_directory
+            .join(format!("systemd-zram-setup@{}.service.wants", device.name))
+            .join(unit_name);
+        make_symlink(&format!("../{}", unit_name), &symlink_path)?;
     }
+
+    Ok(())
+}
+
+/// A systemd-zram-resize@zramN.service, applying the configured memory-hotplug policy
+fn handle_zram_resize(output_directory: &Path, device: &Device) -> Result<()> {
+    let unit_name = format!("systemd-zram-resize@{}.service", device.name);
+    debug!("Creating unit file {}", unit_name);
+
+    write_contents(
+        output_directory,
+        &unit_name,
+        &format!(
+            "\
+[Unit]
+Description=Resize /dev/{zram_device} after memory hotplug
+Documentation=man:zram-generator(8) man:zram-generator.conf(5)
+After=systemd-zram-setup@{zram_device}.service
+ConditionPathExists=/dev/{zram_device}
+
+[Service]
+Type=oneshot
+ExecStart={exe_name} --resize-device{grow_only} '{zram_device}'
+",
+            zram_device = device.name,
+            exe_name = std::env::current_exe().unwrap().display(),
+            grow_only = match device.memory_hotplug {
+                HotplugPolicy::GrowOnly => " --grow-only",
+                _ => "",
+            },
+        ),
+    )
+}
+
+/// A systemd-zram-{action}@zramN.timer, started and stopped alongside the device,
+/// that periodically runs --{action}-device
+fn handle_zram_periodic(
+    output_directory: &Path,
+    device: &Device,
+    action: &str,
+    description: &str,
+    interval: u64,
+) -> Result<()> {
+    let unit_name = format!("systemd-zram-{}@{}", action, device.name);
+
+    debug!(
+        "Creating unit files {0}.timer and {0}.service (every {1}s)",
+        unit_name, interval
+    );
+
+    write_contents(
+        output_directory,
+        &format!("{}.service", unit_name),
+        &format!(
+            "\
+[Unit]
+Description={description} on /dev/{zram_device}
+Documentation=man:zram-generator(8) man:zram-generator.conf(5)
+Requisite=systemd-zram-setup@{zram_device}.service
+After=systemd-zram-setup@{zram_device}.service
+
+[Service]
+Type=oneshot
+ExecStart={exe_name} --{action}-device '{zram_device}'
+",
+            description = description,
+            zram_device = device.name,
+            exe_name = std::env::current_exe().unwrap().display(),
+            action = action,
+        ),
+    )?;
+
+    write_contents(
+        output_directory,
+        &format!("{}.timer", unit_name),
+        &format!(
+            "\
+[Unit]
+Description={description} on /dev/{zram_device} periodically
+Documentation=man:zram-generator(8) man:zram-generator.conf(5)
+BindsTo=systemd-zram-setup@{zram_device}.service
+After=systemd-zram-setup@{zram_device}.service
+
+[Timer]
+OnActiveSec={interval}s
+OnUnitActiveSec={interval}s
+",
+            description = description,
+            zram_device = device.name,
+            interval = interval,
+        ),
+    )?;
+
+    /* enablement symlink: the timer lives as long as the device is set up */
+    let timer_name = format!("{}.timer", unit_name);
+    let symlink_path = output_directory
+        .join(format!("systemd-zram-setup@{}.service.wants", device.name))
+        .join(&timer_name);
+    let target_path = format!("../{}", timer_name);
+    make_symlink(&target_path, &symlink_path)?;
+
+    Ok(())
 }
 
 fn handle_zram_bindings(output_directory: &Path, device: &Device, specific: &str) -> Result<()> {
@@ -289,6 +520,29 @@ fn handle_zram_mount_point(output_directory: &Path, device: &Device) -> Result<(
 
     handle_zram_bindings(output_directory, device, mount_name)?;
 
+    // x-systemd.* options become unit settings, like in systemd-fstab-generator(8)
+    let mut unit_settings = String::new();
+    let mut mount_settings = String::new();
+    let mut wanted_by: Vec<&str> = device.wanted_by.iter().flatten().map(|t| &t[..]).collect();
+    let mut required_by = vec![];
+    if !device.before.is_empty() {
+        unit_settings += &format!("Before={}\n", device.before.join(" "));
+    }
+    for (name, value) in device.x_systemd_options() {
+        match name {
+            "before" => unit_settings += &format!("Before={}\n", value),
+            "after" => unit_settings += &format!("After={}\n", value),
+            "requires" => unit_settings += &format!("Requires={}\nAfter={}\n", value, value),
+            "wanted-by" => wanted_by.push(value),
+            "required-by" => required_by.push(value),
+            "mount-timeout" => mount_settings += &format!("TimeoutSec={}\n", value),
+            _ => {} // rejected by parse_line()
+        }
+    }
+    if device.wanted_by.is_none() && wanted_by.is_empty() && required_by.is_empty() {
+        wanted_by.push("local-fs.target");
+    }
+
     write_contents(
         output_directory,
         mount_name,
@@ -299,24 +553,32 @@ Description=Compressed Storage on /dev/{zram_device}
 Documentation=man:zram-generator(8) man:zram-generator.conf(5)
 Requires=systemd-zram-setup@{zram_device}.service
 After=systemd-zram-setup@{zram_device}.service
-
+{unit_settings}
 [Mount]
 What=/dev/{zram_device}
 Where={mount_point}
+Type={fs_type}
 Options={options}
-",
+{mount_settings}",
             zram_device = device.name,
+            unit_settings = unit_settings,
             mount_point = device.mount_point.as_ref().unwrap().to_str().unwrap(),
-            options = device.options.replace('%', "%%"),
+            fs_type = device.effective_fs_type(),
+            options = device.mount_options().replace('%', "%%"),
+            mount_settings = mount_settings,
         ),
     )?;
 
-    /* enablement symlink */
-    let symlink_path = output_directory
-        .join("local-fs.target.wants")
-        .join(&mount_name);
+    /* enablement symlinks */
     let target_path = format!("../{}", mount_name);
-    make_symlink(&target_path, &symlink_path)?;
+    let wants = wanted_by.into_iter().map(|unit| (unit, "wants"));
+    let requires = required_by.into_iter().map(|unit| (unit, "requires"));
+    for (unit, kind) in wants.chain(requires).collect::<BTreeSet<_>>() {
+        let symlink_path = output_directory
+            .join(format!("{}.{}", unit, kind))
+            .join(mount_name);
+        make_symlink(&target_path, &symlink_path)?;
+    }
 
     Ok(())
 }
@@ -374,23 +636,23 @@ type         : skcipher
     #[test]
     fn test_unit_name_from_path() {
         assert_eq!(
-            unit_name_from_path(&Path::new("/waldo"), ".mount"),
+            unit_name_from_path(Path::new("/waldo"), ".mount"),
             "waldo.mount"
         );
         assert_eq!(
-            unit_name_from_path(&Path::new("/waldo/quuix"), ".mount"),
+            unit_name_from_path(Path::new("/waldo/quuix"), ".mount"),
             "waldo-quuix.mount"
         );
         assert_eq!(
-            unit_name_from_path(&Path::new("/waldo/quuix/"), ".mount"),
+            unit_name_from_path(Path::new("/waldo/quuix/"), ".mount"),
             "waldo-quuix.mount"
         );
         assert_eq!(
-            unit_name_from_path(&Path::new("/waldo/quuix//"), ".mount"),
+            unit_name_from_path(Path::new("/waldo/quuix//"), ".mount"),
             "waldo-quuix.mount"
         );
-        assert_eq!(unit_name_from_path(&Path::new("/"), ".mount"), "-.mount");
-        assert_eq!(unit_name_from_path(&Path::new("//"), ".mount"), "-.mount");
-        assert_eq!(unit_name_from_path(&Path::new("///"), ".mount"), "-.mount");
+        assert_eq!(unit_name_from_path(Path::new("/"), ".mount"), "-.mount");
+        assert_eq!(unit_name_from_path(Path::new("//"), ".mount"), "-.mount");
+        assert_eq!(unit_name_from_path(Path::new("///"), ".mount"), "-.mount");
     }
 }
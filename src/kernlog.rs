@@ -1,17 +1,36 @@
 /* SPDX-License-Identifier: MIT */
-//! Logger implementation for low level kernel log (using `/dev/kmsg`)
+//! Logger implementation for the journal (using its native protocol)
+//! and low level kernel log (using `/dev/kmsg`)
 //!
-//! Borrowed and cut down from https://github.com/kstep/kernlog.rs/pull/2,
+//! The kmsg part is borrowed and cut down from https://github.com/kstep/kernlog.rs/pull/2,
 //! consider merging changes back when fixing something here;
-//! this automatically falls back to stdout and ignores problems with opening "/dev/kmsg".
+//! the journal is only used when running as a service (`$JOURNAL_STREAM` is set) or not from a terminal,
+//! and this falls back to kmsg if the journal socket can't be connected to (like early at boot)
+//! or a message can't be sent, then to stdout, and ignores problems with opening "/dev/kmsg".
+//!
+//! Key-values attached to records become journal fields, with the key uppercased,
+//! e.g. `info!(zram_device = "zram0"; …)` yields `ZRAM_DEVICE=zram0`.
 
+use std::env;
 use std::fs::{File, OpenOptions};
 use std::io::{self, Write};
+use std::os::unix::net::UnixDatagram;
 use std::process::id;
 use std::sync::Mutex;
 
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+
+extern "C" {
+    fn isatty(fd: i32) -> i32;
+}
+
+fn use_journal() -> bool {
+    env::var_os("JOURNAL_STREAM").is_some() || unsafe { isatty(2) } == 0
+}
+
 /// Kernel logger implementation
 pub struct KernelLog {
+    journal: Option<UnixDatagram>,
     kmsg: Mutex<Option<File>>,
     maxlevel: log::LevelFilter,
 }
@@ -20,26 +39,104 @@ impl KernelLog {
     /// Create new kernel logger with error level filter
     pub fn with_level(level: log::LevelFilter) -> KernelLog {
         KernelLog {
+            journal: if use_journal() {
+                UnixDatagram::unbound()
+                    .and_then(|sock| sock.connect(JOURNAL_SOCKET).map(|_| sock))
+                    .ok()
+            } else {
+                None
+            },
             kmsg: Mutex::new(OpenOptions::new().write(true).open("/dev/kmsg").ok()),
             maxlevel: level,
         }
     }
 }
 
-fn _write_kmsg(kmsg: &mut File, record: &log::Record) {
-    let level: u8 = match record.level() {
+fn syslog_level(level: log::Level) -> u8 {
+    match level {
         log::Level::Error => 3,
         log::Level::Warn => 4,
         log::Level::Info => 5,
         log::Level::Debug => 6,
         log::Level::Trace => 7,
-    };
+    }
+}
 
+/// Appends a field in the journal's native format:
+/// `NAME=value\n`, or, if the value has newlines, `NAME\n`, its length as 64-bit LE, `value\n`
+fn journal_field(buf: &mut Vec<u8>, name: &str, value: &[u8]) {
+    buf.extend_from_slice(name.as_bytes());
+    if value.contains(&b'\n') {
+        buf.push(b'\n');
+        buf.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        buf.push(b'=');
+    }
+    buf.extend_from_slice(value);
+    buf.push(b'\n');
+}
+
+struct JournalFields<'a>(&'a mut Vec<u8>);
+
+impl<'kvs> log::kv::VisitSource<'kvs> for JournalFields<'_> {
+    fn visit_pair(
+        &mut self,
+        key: log::kv::Key<'kvs>,
+        value: log::kv::Value<'kvs>,
+    ) -> Result<(), log::kv::Error> {
+        let mut field = FieldValue(None);
+        value.visit(&mut field)?;
+        if let Some(value) = field.0 {
+            journal_field(self.0, &key.as_str().to_ascii_uppercase(), value.as_bytes());
+        }
+        Ok(())
+    }
+}
+
+/// None for null values, like those of `Option`s that are `None`, which are skipped
+struct FieldValue(Option<String>);
+
+impl<'v> log::kv::VisitValue<'v> for FieldValue {
+    fn visit_any(&mut self, value: log::kv::Value) -> Result<(), log::kv::Error> {
+        self.0 = Some(value.to_string());
+        Ok(())
+    }
+
+    fn visit_null(&mut self) -> Result<(), log::kv::Error> {
+        Ok(())
+    }
+}
+
+fn journal_entry(record: &log::Record) -> Vec<u8> {
+    let mut buf = Vec::new();
+    journal_field(
+        &mut buf,
+        "PRIORITY",
+        syslog_level(record.level()).to_string().as_bytes(),
+    );
+    journal_field(
+        &mut buf,
+        "SYSLOG_IDENTIFIER",
+        env!("CARGO_PKG_NAME").as_bytes(),
+    );
+    journal_field(&mut buf, "SYSLOG_PID", id().to_string().as_bytes());
+    journal_field(&mut buf, "MESSAGE", record.args().to_string().as_bytes());
+    if let Some(file) = record.file() {
+        journal_field(&mut buf, "CODE_FILE", file.as_bytes());
+    }
+    if let Some(line) = record.line() {
+        journal_field(&mut buf, "CODE_LINE", line.to_string().as_bytes());
+    }
+    let _ = record.key_values().visit(&mut JournalFields(&mut buf));
+    buf
+}
+
+fn _write_kmsg(kmsg: &mut File, record: &log::Record) {
     let mut buf = Vec::new();
     writeln!(
         buf,
         "<{}>{}[{}]: {}",
-        level,
+        syslog_level(record.level()),
         record.target(),
         id(),
         record.args()
@@ -67,6 +164,12 @@ impl log::Log for KernelLog {
             return;
         }
 
+        if let Some(ref journal) = self.journal {
+            if journal.send(&journal_entry(record)).is_ok() {
+                return;
+            }
+        }
+
         if let Ok(mut kmsg) = self.kmsg.lock() {
             match kmsg.as_mut() {
                 Some(kmsg) => _write_kmsg(kmsg, record),
@@ -84,3 +187,33 @@ pub fn init_with_level(level: log::LevelFilter) -> Result<(), log::SetLoggerErro
     log::set_max_level(level);
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_journal_entry() {
+        let entry = journal_entry(
+            &log::Record::builder()
+                .args(format_args!("two\nlines"))
+                .level(log::Level::Warn)
+                .file(Some("src/setup.rs"))
+                .line(Some(42))
+                .key_values(&[
+                    ("zram_device", log::kv::Value::from("zram0")),
+                    ("config_line", log::kv::Value::null()),
+                ])
+                .build(),
+        );
+
+        let expected = [
+            &b"PRIORITY=4\nSYSLOG_IDENTIFIER=zram-generator\n"[..],
+            format!("SYSLOG_PID={}\n", id()).as_bytes(),
+            b"MESSAGE\n\x09\0\0\0\0\0\0\0two\nlines\n",
+            b"CODE_FILE=src/setup.rs\nCODE_LINE=42\nZRAM_DEVICE=zram0\n",
+        ]
+        .concat();
+        assert_eq!(entry, expected);
+    }
+}
@@ -2,4 +2,6 @@
 
 pub mod config;
 pub mod generator;
+pub mod message_id;
 pub mod setup;
+pub mod status;
@@ -3,13 +3,16 @@
 mod config;
 mod generator;
 mod kernlog;
+mod message_id;
 mod setup;
+mod status;
 
 use anyhow::Result;
-use clap::{crate_description, crate_name, crate_version, App, Arg};
+use clap::{crate_description, crate_name, crate_version, App, Arg, ArgGroup};
 use log::{info, LevelFilter};
 use std::borrow::Cow;
 use std::env;
+use std::io;
 use std::path::{Path, PathBuf};
 
 #[derive(Debug)]
@@ -18,32 +21,189 @@ enum Opts {
     GenerateUnits(String),
     /// Set up a single device
     SetupDevice(String),
-    /// Reset (destroy) a device
-    ResetDevice(String),
+    /// Reset (destroy) a device, optionally deactivating it first and removing it afterwards
+    ResetDevice(String, bool, bool),
+    /// Resize a device to the configured size, optionally only if larger
+    ResizeDevice(String, bool),
+    /// Recompress pages with the secondary algorithms
+    RecompressDevice(String),
+    /// Write idle and incompressible pages back to the backing device
+    WritebackDevice(String),
+    /// Print the effective configuration
+    DumpConfig,
+    /// Show statistics of one or all devices, optionally as JSON
+    Status(Option<String>, bool),
+    /// Check the configuration, or just the given files
+    Verify(Vec<PathBuf>),
+    /// Set up and activate all configured devices, without systemd
+    SetupAll,
+    /// Deactivate and reset all configured devices, without systemd
+    ResetAll,
+    /// Reset and remove idle zram devices we created that aren't configured
+    Prune,
+    /// Write the udev rules resizing devices after memory hotplug
+    InstallHotplugRules,
+    /// Remove the udev rules resizing devices after memory hotplug
+    RemoveHotplugRules,
+    /// Write and apply the sysctl.d fragment with the [sysctl] settings
+    InstallSysctl,
+    /// Remove the sysctl.d fragment with the [sysctl] settings, restoring the previous values
+    RemoveSysctl,
 }
 
 fn get_opts() -> Opts {
     let opts = App::new(crate_name!())
         .version(crate_version!())
         .about(crate_description!())
+        .arg(Arg::from_usage("--setup-device 'Set up a single device'"))
+        .arg(Arg::from_usage("--reset-device 'Reset (destroy) a device'"))
         .arg(
-            Arg::from_usage("--setup-device 'Set up a single device'")
-                .conflicts_with("reset-device"),
+            Arg::from_usage(
+                "--deactivate 'With --reset-device, swapoff and unmount the device if still in use'",
+            )
+            .requires("reset-device"),
         )
-        .arg(Arg::from_usage("--reset-device 'Reset (destroy) a device'"))
+        .arg(
+            Arg::from_usage("--remove 'With --reset-device, also remove the device'")
+                .requires("reset-device"),
+        )
+        .arg(
+            Arg::from_usage(
+                "--resize-device 'Resize a device to the configured size, even if in use as swap'",
+            )
+            .visible_alias("resize"),
+        )
+        .arg(
+            Arg::from_usage("--grow-only 'With --resize-device, never make the device smaller'")
+                .requires("resize-device"),
+        )
+        .arg(Arg::from_usage(
+            "--recompress-device 'Recompress pages with the secondary algorithms'",
+        ))
+        .arg(Arg::from_usage(
+            "--writeback-device 'Write idle and incompressible pages to the backing device'",
+        ))
+        .arg(
+            Arg::from_usage("--dump-config 'Print the effective configuration'")
+                .conflicts_with("directory|device"),
+        )
+        .arg(Arg::from_usage(
+            "--status 'Show statistics of the device, or all zram devices'",
+        ))
+        .arg(
+            Arg::from_usage("--verify [file]... 'Check the configuration, or just these files'")
+                .min_values(0),
+        )
+        .arg(Arg::from_usage(
+            "--setup-all 'Set up and activate all configured devices, without systemd'",
+        ))
+        .arg(Arg::from_usage(
+            "--reset-all 'Deactivate and reset all configured devices, without systemd'",
+        ))
+        .arg(Arg::from_usage(
+            "--prune 'Reset and remove idle zram devices we created that are not configured'",
+        ))
+        .arg(Arg::from_usage(
+            "--install-hotplug-rules 'Write the udev rules resizing devices after memory hotplug'",
+        ))
+        .arg(Arg::from_usage(
+            "--remove-hotplug-rules 'Remove the udev rules resizing devices after memory hotplug'",
+        ))
+        .arg(Arg::from_usage(
+            "--install-sysctl 'Write and apply the sysctl.d fragment with the [sysctl] settings'",
+        ))
         .arg(Arg::from_usage(
-            "<directory|device> 'Target directory for generator or device to operate on'",
+            "--remove-sysctl 'Remove the sysctl.d fragment, restoring the previous values'",
         ))
+        .group(ArgGroup::with_name("mode").args(&[
+            "setup-device",
+            "reset-device",
+            "resize-device",
+            "recompress-device",
+            "writeback-device",
+            "dump-config",
+            "status",
+            "verify",
+            "setup-all",
+            "reset-all",
+            "prune",
+            "install-hotplug-rules",
+            "remove-hotplug-rules",
+            "install-sysctl",
+            "remove-sysctl",
+        ]))
+        .arg(Arg::from_usage("--json 'Format --status output as JSON'").requires("status"))
+        .arg(
+            Arg::from_usage(
+                "<directory|device> 'Target directory for generator or device to operate on'",
+            )
+            .required_unless_one(&[
+                "dump-config",
+                "status",
+                "verify",
+                "setup-all",
+                "reset-all",
+                "prune",
+                "install-hotplug-rules",
+                "remove-hotplug-rules",
+                "install-sysctl",
+                "remove-sysctl",
+            ]),
+        )
         .arg(
             Arg::from_usage(
                 "[extra-dir] 'Unused target directories to satisfy systemd.generator(5)'",
             )
             .number_of_values(2)
-            .conflicts_with_all(&["setup-device", "reset-device"]),
+            .conflicts_with("mode"),
         )
-        .after_help(&*format!("Uses {}.", setup::SYSTEMD_MAKEFS_COMMAND))
+        .after_help(&*format!(
+            "Uses {} and {}.",
+            setup::SYSTEMD_MAKEFS_COMMAND,
+            setup::SYSTEMCTL_COMMAND
+        ))
         .get_matches();
 
+    if opts.is_present("dump-config") {
+        return Opts::DumpConfig;
+    }
+    if opts.is_present("verify") {
+        return Opts::Verify(
+            opts.values_of_os("verify")
+                .into_iter()
+                .flatten()
+                .map(PathBuf::from)
+                .collect(),
+        );
+    }
+    if opts.is_present("setup-all") {
+        return Opts::SetupAll;
+    }
+    if opts.is_present("reset-all") {
+        return Opts::ResetAll;
+    }
+    if opts.is_present("prune") {
+        return Opts::Prune;
+    }
+    if opts.is_present("install-hotplug-rules") {
+        return Opts::InstallHotplugRules;
+    }
+    if opts.is_present("remove-hotplug-rules") {
+        return Opts::RemoveHotplugRules;
+    }
+    if opts.is_present("install-sysctl") {
+        return Opts::InstallSysctl;
+    }
+    if opts.is_present("remove-sysctl") {
+        return Opts::RemoveSysctl;
+    }
+    if opts.is_present("status") {
+        return Opts::Status(
+            opts.value_of("directory|device").map(str::to_string),
+            opts.is_present("json"),
+        );
+    }
+
     let val = opts
         .value_of("directory|device")
         .expect("clap invariant")
@@ -51,7 +211,17 @@ fn get_opts() -> Opts {
     if opts.is_present("setup-device") {
         Opts::SetupDevice(val)
     } else if opts.is_present("reset-device") {
-        Opts::ResetDevice(val)
+        Opts::ResetDevice(
+            val,
+            opts.is_present("deactivate"),
+            opts.is_present("remove"),
+        )
+    } else if opts.is_present("resize-device") {
+        Opts::ResizeDevice(val, opts.is_present("grow-only"))
+    } else if opts.is_present("recompress-device") {
+        Opts::RecompressDevice(val)
+    } else if opts.is_present("writeback-device") {
+        Opts::WritebackDevice(val)
     } else {
         Opts::GenerateUnits(val)
     }
@@ -74,20 +244,84 @@ fn main() -> Result<()> {
         Some(true) => true,
     };
 
+    // In fake mode, don't let the machine running it affect condition-virtualization=
+    let virtualization = || match have_env_var {
+        true => config::Virtualization::fake("none"),
+        false => config::Virtualization::default(),
+    };
+
     match get_opts() {
         Opts::GenerateUnits(target) => {
-            let devices = config::read_all_devices(&root, kernel_override())?;
+            let config = config::read_config(&root, kernel_override(), virtualization())?;
             let output_directory = PathBuf::from(target);
-            generator::run_generator(&devices, &output_directory, have_env_var)
+            generator::run_generator(&config, &output_directory, have_env_var)
         }
         Opts::SetupDevice(dev) => {
-            let device = config::read_device(&root, kernel_override(), &dev)?;
+            let device = config::read_device(&root, kernel_override(), virtualization(), &dev)?;
             setup::run_device_setup(device, &dev)
         }
-        Opts::ResetDevice(dev) => {
+        Opts::ResetDevice(dev, deactivate, remove) => {
             // We don't read the config here, so that it's possible to remove a device
             // even after the config has been removed.
-            setup::run_device_reset(&dev)
+            setup::run_device_reset(&dev, deactivate, remove)
+        }
+        Opts::ResizeDevice(dev, grow_only) => {
+            let device = config::read_device(&root, kernel_override(), virtualization(), &dev)?;
+            setup::run_device_resize(device, &dev, grow_only)
+        }
+        Opts::RecompressDevice(dev) => {
+            let device = config::read_device(&root, kernel_override(), virtualization(), &dev)?;
+            setup::run_device_recompress(device, &dev)
+        }
+        Opts::WritebackDevice(dev) => {
+            let device = config::read_device(&root, kernel_override(), virtualization(), &dev)?;
+            setup::run_device_writeback(device, &dev)
+        }
+        Opts::DumpConfig => config::dump_config(
+            &root,
+            kernel_override(),
+            virtualization(),
+            &mut io::stdout(),
+        ),
+        Opts::Status(dev, json) => status::run_status(&root, dev.as_deref(), json),
+        Opts::SetupAll => {
+            let devices = config::read_all_devices(&root, kernel_override(), virtualization())?;
+            setup::run_setup_all(&devices)
+        }
+        Opts::ResetAll => {
+            let devices = config::read_all_devices(&root, kernel_override(), virtualization())?;
+            setup::run_reset_all(&devices)
+        }
+        Opts::Prune => {
+            let devices = config::read_all_devices(&root, kernel_override(), virtualization())?;
+            setup::run_prune(&devices)
+        }
+        Opts::InstallHotplugRules => {
+            let devices = config::read_all_devices(&root, kernel_override(), virtualization())?;
+            setup::run_install_hotplug_rules(&devices)
+        }
+        Opts::RemoveHotplugRules => setup::run_remove_hotplug_rules(),
+        Opts::InstallSysctl => {
+            let config = config::read_config(&root, kernel_override(), virtualization())?;
+            setup::run_install_sysctl(&config)
+        }
+        Opts::RemoveSysctl => setup::run_remove_sysctl(),
+        Opts::Verify(files) => {
+            let problems = config::verify_config(&root, &files)?;
+            for problem in &problems {
+                let kind = match problem.severity {
+                    config::Severity::Advisory => "warning",
+                    _ => "error",
+                };
+                println!("{}: {}: {:#}", problem.location(), kind, problem.cause);
+            }
+            if problems
+                .iter()
+                .any(|p| p.severity != config::Severity::Advisory)
+            {
+                std::process::exit(1);
+            }
+            Ok(())
         }
     }
 }
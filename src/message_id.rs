@@ -0,0 +1,18 @@
+/* SPDX-License-Identifier: MIT */
+//! `MESSAGE_ID=`s attached to journal entries, one per kind of event,
+//! so that they can be found with e.g. `journalctl MESSAGE_ID=…`
+
+/// A device is not created because of `host-memory-limit`, `host-memory-minimum`, or a `condition-*` setting
+pub const DEVICE_DISABLED: &str = "cbb74b4416684914b71d562cfb4a2280";
+/// A line or section of a configuration fragment is ignored
+pub const CONFIG_IGNORED: &str = "c6643776f53344fdb07d90a593bdcd56";
+/// A section or file of a configuration fragment is skipped because of an invalid value
+pub const CONFIG_SKIPPED: &str = "6a2fd5deacf9415eb6356744feaeb151";
+/// The kernel doesn't support a configured (re)compression algorithm
+pub const ALGORITHM_UNSUPPORTED: &str = "fa89b086ef614f7391afd430438d1a54";
+/// `writeback-limit` is reached during writeback
+pub const WRITEBACK_LIMIT_REACHED: &str = "80903432c849433a90e0bb9d87c2daf9";
+/// A device is set up and formatted
+pub const DEVICE_SET_UP: &str = "d5a8e3dafdc0482d83eb71f879057218";
+/// A device is reset
+pub const DEVICE_RESET: &str = "88161380cb1042379f3c21fc333e01a9";
@@ -1,14 +1,54 @@
 /* SPDX-License-Identifier: MIT */
 
-use crate::config::Device;
+use crate::config::{Algorithm, Config, Device, HotplugPolicy, TUNABLES};
+use crate::generator;
+use crate::message_id;
 use anyhow::{anyhow, Context, Result};
-use log::warn;
+use log::{debug, error, info, warn};
+use std::collections::BTreeMap;
+use std::fmt;
 use std::fs;
-use std::io::ErrorKind;
+use std::io::{self, ErrorKind};
 use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
 use std::os::unix::process::ExitStatusExt;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::thread;
+use std::time::Duration;
+
+/// How often to try writing `reset` and `hot_remove` while the device is busy
+const RESET_ATTEMPTS: u32 = 6;
+/// From asm-generic/errno-base.h, the same on all architectures
+const EBUSY: i32 = 16;
+/// Doubled after each busy attempt, for about 3s total
+const RESET_BACKOFF: Duration = Duration::from_millis(50);
+
+/// `writeback_limit` is in units of 4K pages, regardless of `PAGE_SIZE`
+const WRITEBACK_LIMIT_PAGES_PER_MB: u64 = 1024 * 1024 / 4096;
+
+/// Where new file systems are mounted to set the mode and owner of their root directory
+const ROOT_ATTRIBUTES_MOUNT_DIR: &str = "/run/zram-generator";
+/// Has an empty file named after each device we created or set up, the only ones `--prune` may remove
+const CREATED_DEVICES_DIR: &str = "/run/zram-generator/created";
+
+/// Used by the memory hotplug udev rules to start resize services without waiting for them
+pub const SYSTEMCTL_COMMAND: &str = match option_env!("SYSTEMCTL") {
+    Some(systemctl) => systemctl,
+    None => "/usr/bin/systemctl",
+};
+
+/// Read by udev, installed by systemd-zram-hotplug.service
+const HOTPLUG_RULES_PATH: &str = "/run/udev/rules.d/90-zram-generator-hotplug.rules";
+
+/// Read by systemd-sysctl, installed by systemd-zram-sysctl.service
+const SYSCTL_PATH: &str = "/run/sysctl.d/90-zram-generator.conf";
+
+/// The values the settings had before being changed there, in the same format, restored by `--remove-sysctl`
+const SYSCTL_SAVED_PATH: &str = "/run/zram-generator/sysctl-saved.conf";
+
+/// Applies all sysctl.d(5) fragments, so that later ones still take precedence over ours
+const SYSTEMD_SYSCTL_COMMAND: &str = concat!(env!("SYSTEMD_UTIL_DIR"), "/systemd-sysctl");
 
 pub const SYSTEMD_MAKEFS_COMMAND: &str = concat!(
     env!(
@@ -21,23 +61,55 @@ pub const SYSTEMD_MAKEFS_COMMAND: &str = concat!(
 
 pub fn run_device_setup(device: Option<Device>, device_name: &str) -> Result<()> {
     let device = device.ok_or_else(|| anyhow!("Device {} not found", device_name))?;
+    setup_device(&device)
+}
 
+fn setup_device(device: &Device) -> Result<()> {
+    let device_name = &device.name[..];
     let device_sysfs_path = Path::new("/sys/block").join(device_name);
 
-    if let Some(ref compression_algorithm) = device.compression_algorithm {
-        let comp_algorithm_path = device_sysfs_path.join("comp_algorithm");
-        match fs::write(&comp_algorithm_path, &compression_algorithm) {
-            Ok(_) => {}
+    // --reset-device --remove removes the device, so recreate it if we're restarted
+    if !device_sysfs_path.exists() {
+        generator::create_devices(std::slice::from_ref(device))?;
+    }
+    mark_created(device_name);
+
+    if !device.compression_algorithms.is_empty() {
+        set_compression_algorithm(&device_sysfs_path, device)?;
+    }
+
+    for (i, alg) in device.recompression_algorithms.iter().enumerate() {
+        let recomp_algorithm_path = device_sysfs_path.join("recomp_algorithm");
+        let priority = alg
+            .param("priority")
+            .map(str::to_string)
+            .unwrap_or_else(|| (i + 1).to_string());
+        match fs::write(
+            &recomp_algorithm_path,
+            format!("algo={} priority={}", alg.name, priority),
+        ) {
+            Ok(_) => write_algorithm_params(&device_sysfs_path, device_name, &priority, alg)?,
             Err(err) if err.kind() == ErrorKind::InvalidInput => {
                 warn!(
-                    "Warning: algorithm {:?} not recognised; consult {} for a list of available ones",
-                    compression_algorithm, comp_algorithm_path.display(),
+                    zram_device = device_name,
+                    message_id = message_id::ALGORITHM_UNSUPPORTED;
+                    "Warning: recompression algorithm {:?} not recognised; consult {} for a list of available ones",
+                    alg.name, recomp_algorithm_path.display(),
+                );
+            }
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                warn!(
+                    zram_device = device_name,
+                    message_id = message_id::ALGORITHM_UNSUPPORTED;
+                    "Warning: {} doesn't exist, kernel doesn't support recompression; ignoring {:?}",
+                    recomp_algorithm_path.display(),
+                    alg.name,
                 );
             }
             err @ Err(_) => err.with_context(|| {
                 format!(
-                    "Failed to configure compression algorithm into {}",
-                    comp_algorithm_path.display()
+                    "Failed to configure recompression algorithm into {}",
+                    recomp_algorithm_path.display()
                 )
             })?,
         }
@@ -51,8 +123,21 @@ pub fn run_device_setup(device: Option<Device>, device_name: &str) -> Result<()>
                 writeback_path.display()
             )
         })?;
+
+        if let Some(limit_mb) = device.writeback_limit_mb {
+            let writeback_limit_enable_path = device_sysfs_path.join("writeback_limit_enable");
+            fs::write(&writeback_limit_enable_path, b"1").with_context(|| {
+                format!(
+                    "Failed to enable write-back limit in {}",
+                    writeback_limit_enable_path.display()
+                )
+            })?;
+            set_writeback_limit(&device_sysfs_path, limit_mb)?;
+        }
     }
 
+    set_tunables(&device_sysfs_path, device, true)?;
+
     let disksize_path = device_sysfs_path.join("disksize");
     fs::write(&disksize_path, format!("{}", device.disksize)).with_context(|| {
         format!(
@@ -61,11 +146,28 @@ pub fn run_device_setup(device: Option<Device>, device_name: &str) -> Result<()>
         )
     })?;
 
+    if device.mem_limit_bytes > 0 {
+        let mem_limit_path = device_sysfs_path.join("mem_limit");
+        fs::write(&mem_limit_path, format!("{}", device.mem_limit_bytes)).with_context(|| {
+            format!(
+                "Failed to configure memory limit into {}",
+                mem_limit_path.display()
+            )
+        })?;
+    }
+
+    set_tunables(&device_sysfs_path, device, false)?;
+
     let fs_type = device.effective_fs_type();
     match Command::new(SYSTEMD_MAKEFS_COMMAND).arg(fs_type).arg(Path::new("/dev").join(device_name)).status() {
         Ok(status) =>
             match status.code() {
-                Some(0) => Ok(()),
+                Some(0) => {
+                    set_root_attributes(device)?;
+                    info!(zram_device = device_name, message_id = message_id::DEVICE_SET_UP;
+                          "{}: set up with {} bytes as {}", device_name, device.disksize, fs_type);
+                    Ok(())
+                }
                 Some(code) => Err(anyhow!("{} failed with exit code {}", SYSTEMD_MAKEFS_COMMAND, code)),
                 None => Err(anyhow!("{} terminated by signal {}",
                                     SYSTEMD_MAKEFS_COMMAND,
@@ -83,8 +185,1239 @@ pub fn run_device_setup(device: Option<Device>, device_name: &str) -> Result<()>
     }
 }
 
-pub fn run_device_reset(device_name: &str) -> Result<()> {
-    let reset = Path::new("/sys/block").join(device_name).join("reset");
-    fs::write(reset, b"1")?;
+/// Give the root directory of a freshly made file system its configured mode and owner,
+/// by mounting it on a temporary directory, since systemd-makefs leaves it owned by root, 0755
+fn set_root_attributes(device: &Device) -> Result<()> {
+    if device.is_swap() || (device.root_mode.is_none() && device.root_owner.is_none()) {
+        return Ok(());
+    }
+
+    let dir = Path::new(ROOT_ATTRIBUTES_MOUNT_DIR).join(&device.name);
+    fs::create_dir_all(&dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    run_command(
+        Command::new("mount")
+            .arg("-t")
+            .arg(device.effective_fs_type())
+            .arg(Path::new("/dev").join(&device.name))
+            .arg(&dir),
+    )?;
+
+    let mut result = Ok(());
+    if let Some(mode) = device.root_mode {
+        result = fs::set_permissions(&dir, fs::Permissions::from_mode(mode))
+            .with_context(|| format!("Failed to set mode {:04o} on {}", mode, dir.display()));
+    }
+    if let Some(ref owner) = device.root_owner {
+        result = result.and_then(|_| run_command(Command::new("chown").arg(owner).arg(&dir)));
+    }
+
+    let unmounted = run_command(Command::new("umount").arg(&dir));
+    let _ = fs::remove_dir(&dir);
+    result.and(unmounted)
+}
+
+/// Write the configured tunables that go before (or after) disksize, warning about ones the kernel lacks
+fn set_tunables(device_sysfs_path: &Path, device: &Device, before_disksize: bool) -> Result<()> {
+    for tunable in TUNABLES
+        .iter()
+        .filter(|tunable| tunable.before_disksize == before_disksize)
+    {
+        let value = match device.tunables.get(tunable.key) {
+            Some(value) => value,
+            None => continue,
+        };
+        let path = device_sysfs_path.join(tunable.attribute);
+        match fs::write(&path, value.to_string()) {
+            Ok(_) => {}
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                warn!(
+                    zram_device = device.name.as_str();
+                    "Warning: {} doesn't exist, kernel doesn't support {}; ignoring.",
+                    path.display(), tunable.key,
+                );
+            }
+            err @ Err(_) => err.with_context(|| {
+                format!(
+                    "Failed to configure {} into {}",
+                    tunable.key,
+                    path.display()
+                )
+            })?,
+        }
+    }
+    Ok(())
+}
+
+/// Use the first of the device's compression algorithms the kernel accepts, loading its module if needed
+fn set_compression_algorithm(device_sysfs_path: &Path, device: &Device) -> Result<()> {
+    let device_name = &device.name[..];
+    let comp_algorithm_path = device_sysfs_path.join("comp_algorithm");
+    for alg in &device.compression_algorithms {
+        let mut res = fs::write(&comp_algorithm_path, &alg.name);
+        if matches!(res, Err(ref err) if err.kind() == ErrorKind::InvalidInput) {
+            generator::modprobe(&format!("crypto-{}", alg.name), false);
+            res = fs::write(&comp_algorithm_path, &alg.name);
+        }
+
+        match res {
+            Ok(_) => {
+                if device.compression_algorithms.len() > 1 {
+                    info!(zram_device = device_name; "{}: using compression algorithm {}", device_name, alg.name);
+                }
+                return write_algorithm_params(device_sysfs_path, device_name, 0, alg);
+            }
+            Err(err) if err.kind() == ErrorKind::InvalidInput => {
+                debug!(
+                    "{}: compression algorithm {} not available",
+                    device_name, alg.name
+                );
+            }
+            err @ Err(_) => err.with_context(|| {
+                format!(
+                    "Failed to configure compression algorithm into {}",
+                    comp_algorithm_path.display()
+                )
+            })?,
+        }
+    }
+
+    warn!(
+        zram_device = device_name,
+        message_id = message_id::ALGORITHM_UNSUPPORTED;
+        "Warning: {} not recognised; consult {} for a list of available ones, using the kernel default",
+        match device.compression_algorithms.len() {
+            1 => format!("algorithm {:?}", device.compression_algorithms[0].name),
+            _ => format!("none of the algorithms {:?}",
+                         device.compression_algorithms.iter().map(|alg| &alg.name).collect::<Vec<_>>()),
+        },
+        comp_algorithm_path.display(),
+    );
+    Ok(())
+}
+
+/// Write the algorithm's parameters other than priority, if any, for the algorithm at `priority` (0 for the primary one).
+/// Must be done before setting disksize.
+fn write_algorithm_params(
+    device_sysfs_path: &Path,
+    device_name: &str,
+    priority: impl fmt::Display,
+    alg: &Algorithm,
+) -> Result<()> {
+    let params: String = alg
+        .params
+        .iter()
+        .filter(|(k, _)| k != "priority")
+        .map(|(k, v)| format!(" {}={}", k, v))
+        .collect();
+    if params.is_empty() {
+        return Ok(());
+    }
+
+    let algorithm_params_path = device_sysfs_path.join("algorithm_params");
+    match fs::write(
+        &algorithm_params_path,
+        format!("priority={}{}", priority, params),
+    ) {
+        Ok(_) => Ok(()),
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            warn!(
+                zram_device = device_name,
+                message_id = message_id::ALGORITHM_UNSUPPORTED;
+                "Warning: {} doesn't exist, kernel doesn't support algorithm parameters; using {} with defaults",
+                algorithm_params_path.display(), alg.name,
+            );
+            Ok(())
+        }
+        Err(err) if err.kind() == ErrorKind::InvalidInput => {
+            warn!(
+                zram_device = device_name,
+                message_id = message_id::ALGORITHM_UNSUPPORTED;
+                "Warning: parameters{} not accepted for {}; using it with defaults",
+                params, alg.name,
+            );
+            Ok(())
+        }
+        Err(err) => Err(err).with_context(|| {
+            format!(
+                "Failed to configure algorithm parameters into {}",
+                algorithm_params_path.display()
+            )
+        }),
+    }
+}
+
+pub fn run_device_recompress(device: Option<Device>, device_name: &str) -> Result<()> {
+    let device = device.ok_or_else(|| anyhow!("Device {} not found", device_name))?;
+    if device.recompression_algorithms.is_empty() {
+        return Err(anyhow!(
+            "Device {} has no recompression-algorithm configured",
+            device_name
+        ));
+    }
+
+    let device_sysfs_path = Path::new("/sys/block").join(device_name);
+
+    let mut params = format!("type={}", device.recompression_type);
+    if let Some(threshold) = device.recompression_threshold {
+        params += &format!(" threshold={}", threshold);
+    }
+    let recompress_path = device_sysfs_path.join("recompress");
+    fs::write(&recompress_path, &params).with_context(|| {
+        format!(
+            "Failed to write {:?} into {}",
+            params,
+            recompress_path.display()
+        )
+    })?;
+
+    /* Pages not accessed until the next run will be recompressed then. */
+    if device.recompression_type != "huge" {
+        let idle_path = device_sysfs_path.join("idle");
+        fs::write(&idle_path, b"all")
+            .with_context(|| format!("Failed to mark pages idle in {}", idle_path.display()))?;
+    }
+
+    Ok(())
+}
+
+fn set_writeback_limit(device_sysfs_path: &Path, limit_mb: u64) -> Result<()> {
+    let writeback_limit_path = device_sysfs_path.join("writeback_limit");
+    fs::write(
+        &writeback_limit_path,
+        format!("{}", limit_mb * WRITEBACK_LIMIT_PAGES_PER_MB),
+    )
+    .with_context(|| {
+        format!(
+            "Failed to configure write-back limit into {}",
+            writeback_limit_path.display()
+        )
+    })
+}
+
+fn writeback_limit_exhausted(device_sysfs_path: &Path) -> bool {
+    fs::read_to_string(device_sysfs_path.join("writeback_limit"))
+        .map(|limit| limit.trim() == "0")
+        .unwrap_or(false)
+}
+
+pub fn run_device_writeback(device: Option<Device>, device_name: &str) -> Result<()> {
+    let device = device.ok_or_else(|| anyhow!("Device {} not found", device_name))?;
+    if device.writeback_dev.is_none() {
+        return Err(anyhow!(
+            "Device {} has no writeback-device configured",
+            device_name
+        ));
+    }
+
+    let device_sysfs_path = Path::new("/sys/block").join(device_name);
+    let idle_path = device_sysfs_path.join("idle");
+    let writeback_path = device_sysfs_path.join("writeback");
+
+    /* writeback_limit is only set up once: the kernel decrements it with each page written,
+     * so it caps the total written until the device is reset, not each run. */
+
+    if let Some(age) = device.writeback_idle_age {
+        fs::write(&idle_path, format!("{}", age)).with_context(|| {
+            format!(
+                "Failed to mark pages older than {}s idle in {}",
+                age,
+                idle_path.display()
+            )
+        })?;
+    }
+
+    for kind in ["huge", "idle"] {
+        match fs::write(&writeback_path, kind) {
+            Ok(_) => {}
+            Err(_)
+                if device.writeback_limit_mb.is_some()
+                    && writeback_limit_exhausted(&device_sysfs_path) =>
+            {
+                warn!(
+                    zram_device = device_name,
+                    message_id = message_id::WRITEBACK_LIMIT_REACHED;
+                    "{}: writeback-limit reached, not writing back {} pages",
+                    device_name, kind
+                );
+                break;
+            }
+            err @ Err(_) => err.with_context(|| {
+                format!(
+                    "Failed to write back {} pages via {}",
+                    kind,
+                    writeback_path.display()
+                )
+            })?,
+        }
+    }
+
+    /* Pages not accessed until the next run will be written back then. */
+    if device.writeback_idle_age.is_none() {
+        fs::write(&idle_path, b"all")
+            .with_context(|| format!("Failed to mark pages idle in {}", idle_path.display()))?;
+    }
+
+    Ok(())
+}
+
+/// Reset the device, and, with `remove`, remove it;
+/// if it's still in use, fail, or, with `deactivate`, stop using it first
+pub fn run_device_reset(device_name: &str, deactivate: bool, remove: bool) -> Result<()> {
+    let device_sysfs_path = Path::new("/sys/block").join(device_name);
+    if !device_sysfs_path.exists() {
+        info!(zram_device = device_name; "{}: already removed", device_name);
+        return Ok(());
+    }
+
+    let users = DeviceUsers::find(device_name)?;
+    if !users.is_empty() {
+        if !deactivate {
+            return Err(anyhow!(
+                "{} is in use ({}); stop the units using it, or pass --deactivate",
+                device_name,
+                users
+            ));
+        }
+        users.deactivate(device_name)?;
+    }
+
+    reset_device(device_name, remove, RESET_ATTEMPTS)
+}
+
+/// Write `reset`, then, with `remove`, `hot_remove`, each up to `attempts` times while the device is busy
+fn reset_device(device_name: &str, remove: bool, attempts: u32) -> Result<()> {
+    let device_sysfs_path = Path::new("/sys/block").join(device_name);
+    write_retrying_busy(&device_sysfs_path.join("reset"), "1", attempts)?;
+    info!(zram_device = device_name, message_id = message_id::DEVICE_RESET; "{}: reset", device_name);
+
+    // Not available before Linux 4.2
+    let hot_remove = Path::new("/sys/class/zram-control/hot_remove");
+    if remove && hot_remove.exists() {
+        write_retrying_busy(hot_remove, &device_name["zram".len()..], attempts)?;
+        info!(zram_device = device_name; "{}: removed", device_name);
+        let _ = fs::remove_file(Path::new(CREATED_DEVICES_DIR).join(device_name));
+    }
+
+    Ok(())
+}
+
+/// Write `value` to the sysfs attribute, retrying with exponential backoff while it reports `EBUSY`
+fn write_retrying_busy(path: &Path, value: &str, attempts: u32) -> Result<()> {
+    let mut backoff = RESET_BACKOFF;
+    for attempt in 1.. {
+        match fs::write(path, value) {
+            Err(e) if e.raw_os_error() == Some(EBUSY) && attempt < attempts => {
+                thread::sleep(backoff);
+                backoff *= 2;
+            }
+            res => {
+                return res.with_context(|| {
+                    format!(
+                        "Failed to write {} to {} (after {} attempts)",
+                        value,
+                        path.display(),
+                        attempt
+                    )
+                })
+            }
+        }
+    }
+    unreachable!()
+}
+
+fn is_busy(e: &anyhow::Error) -> bool {
+    matches!(e.root_cause().downcast_ref::<io::Error>(), Some(e) if e.raw_os_error() == Some(EBUSY))
+}
+
+/// Change the size of the device to the currently configured one,
+/// moving swapped-out pages through a temporary device if it's in use as swap
+pub fn run_device_resize(device: Option<Device>, device_name: &str, grow_only: bool) -> Result<()> {
+    let device = device.ok_or_else(|| anyhow!("Device {} not found", device_name))?;
+    let device_sysfs_path = Path::new("/sys/block").join(device_name);
+    if !device_sysfs_path.exists() {
+        return setup_device(&device);
+    }
+
+    let disksize_path = device_sysfs_path.join("disksize");
+    let current: u64 = fs::read_to_string(&disksize_path)
+        .with_context(|| format!("Failed to read {}", disksize_path.display()))?
+        .trim()
+        .parse()
+        .with_context(|| format!("Failed to parse {}", disksize_path.display()))?;
+    let users = DeviceUsers::find(device_name)?;
+    match plan_resize(current, device.disksize, grow_only, &users) {
+        ResizePlan::Keep => {
+            info!(zram_device = device_name; "{}: already {} bytes, nothing to do.", device_name, current);
+            return Ok(());
+        }
+        ResizePlan::KeepLarger => {
+            info!(zram_device = device_name;
+                  "{}: not shrinking from {} to {} bytes.", device_name, current, device.disksize);
+            return Ok(());
+        }
+        ResizePlan::Mounted => {
+            return Err(anyhow!(
+                "{} is {}; file systems can't be resized",
+                device_name,
+                users
+            ));
+        }
+        ResizePlan::InPlace => {
+            write_retrying_busy(&device_sysfs_path.join("reset"), "1", RESET_ATTEMPTS)?;
+            setup_device(&device)?;
+        }
+        ResizePlan::ThroughTemporary => resize_through_temporary(&device)?,
+    }
+
+    info!(zram_device = device_name; "{}: resized from {} to {} bytes", device_name, current, device.disksize);
+    Ok(())
+}
+
+#[derive(Debug, PartialEq)]
+enum ResizePlan {
+    /// Already the configured size
+    Keep,
+    /// Only growing was requested, but the configured size is smaller
+    KeepLarger,
+    /// Mounted file systems can't be resized
+    Mounted,
+    /// Unused, so it can just be reset and set up again
+    InPlace,
+    /// In use as swap, see `resize_through_temporary()`
+    ThroughTemporary,
+}
+
+fn plan_resize(current: u64, wanted: u64, grow_only: bool, users: &DeviceUsers) -> ResizePlan {
+    if current == wanted {
+        ResizePlan::Keep
+    } else if grow_only && wanted < current {
+        ResizePlan::KeepLarger
+    } else if !users.mounts.is_empty() {
+        ResizePlan::Mounted
+    } else if users.swap {
+        ResizePlan::ThroughTemporary
+    } else {
+        ResizePlan::InPlace
+    }
+}
+
+/// The swap unit generated for the device, if systemd is running
+fn generated_swap_unit(device_name: &str) -> Option<String> {
+    let unit = format!("dev-{}.swap", device_name);
+    if Path::new("/run/systemd/system").exists()
+        && Path::new("/run/systemd/generator").join(&unit).exists()
+    {
+        Some(unit)
+    } else {
+        None
+    }
+}
+
+/// Stop the swap unit, which stops the bound setup service, resetting the device, then start both again;
+/// the resize service isn't bound to them, so it isn't stopped along
+fn restart_swap_unit(unit: &str, run: &mut dyn FnMut(&mut Command) -> Result<()>) -> Result<()> {
+    for action in ["stop", "start"] {
+        run(Command::new(SYSTEMCTL_COMMAND).arg(action).arg(unit))?;
+    }
     Ok(())
 }
+
+/// Swap on a temporary device, so that the original one can be swapped off, recreated, and swapped on again;
+/// under systemd, that's done by restarting its swap unit, whose setup service resets the device when stopped,
+/// so that systemd's view of the swap stays right
+fn resize_through_temporary(device: &Device) -> Result<()> {
+    // Without the backing device, which can only be attached to one device at a time
+    let temporary = Device {
+        compression_algorithms: device.compression_algorithms.clone(),
+        recompression_algorithms: device.recompression_algorithms.clone(),
+        disksize: device.disksize,
+        mem_limit_bytes: device.mem_limit_bytes,
+        swap_priority: device.swap_priority,
+        options: device.options.clone(),
+        tunables: device.tunables.clone(),
+        ..Device::new(format!("zram{}", hot_add()?))
+    };
+    info!(zram_device = device.name.as_str();
+          "{}: moving swap to temporary device {}", device.name, temporary.name);
+
+    let res = setup_device(&temporary)
+        .and_then(|_| activate_device(&temporary))
+        .and_then(|_| match generated_swap_unit(&device.name) {
+            Some(unit) => restart_swap_unit(&unit, &mut run_command),
+            None => DeviceUsers {
+                swap: true,
+                mounts: vec![],
+            }
+            .deactivate(&device.name)
+            .and_then(|_| {
+                write_retrying_busy(
+                    &Path::new("/sys/block").join(&device.name).join("reset"),
+                    "1",
+                    RESET_ATTEMPTS,
+                )
+            })
+            .and_then(|_| setup_device(device))
+            .and_then(|_| activate_device(device)),
+        });
+
+    // Remove the temporary device, unless the original one isn't swap again and the pages must stay there
+    match res {
+        Ok(()) => run_device_reset(&temporary.name, true, true),
+        Err(e) => {
+            if matches!(DeviceUsers::find(&device.name), Ok(users) if users.swap) {
+                if let Err(e) = run_device_reset(&temporary.name, true, true) {
+                    warn!(zram_device = temporary.name.as_str(); "{}: {:#}", temporary.name, e);
+                }
+            } else {
+                warn!(zram_device = temporary.name.as_str();
+                      "{}: keeping temporary device {} in use as swap.", device.name, temporary.name);
+            }
+            Err(e)
+        }
+    }
+}
+
+/// Create a new device, returning its number
+pub(crate) fn hot_add() -> Result<u64> {
+    let number = fs::read_to_string("/sys/class/zram-control/hot_add")
+        .context("Adding zram device")?
+        .trim_end()
+        .parse::<u64>()
+        .context("Fresh zram device number")?;
+    mark_created(&format!("zram{}", number));
+    Ok(number)
+}
+
+fn mark_created(device_name: &str) {
+    let dir = Path::new(CREATED_DEVICES_DIR);
+    if let Err(e) = fs::create_dir_all(dir).and_then(|_| fs::write(dir.join(device_name), b"")) {
+        warn!(zram_device = device_name;
+              "{}: failed to record it in {}, --prune will leave it alone: {}",
+              device_name, dir.display(), e);
+    }
+}
+
+fn is_created(device_name: &str) -> bool {
+    Path::new(CREATED_DEVICES_DIR).join(device_name).exists()
+}
+
+/// udev rules starting systemd-zram-resize@zramN.service
+/// when memory is onlined, or also offlined, depending on the device's policy; empty if there are none
+fn hotplug_rules(devices: &[Device]) -> String {
+    let units_for = |policies: &[HotplugPolicy]| {
+        let mut matching: Vec<_> = devices
+            .iter()
+            .filter(|device| device.is_swap() && policies.contains(&device.memory_hotplug))
+            .collect();
+        matching.sort_by_key(|device| device.index());
+        matching
+            .iter()
+            .map(|device| format!(" systemd-zram-resize@{}.service", device.name))
+            .collect::<String>()
+    };
+
+    let mut rules = String::new();
+    for (action, units) in [
+        (
+            "online",
+            units_for(&[HotplugPolicy::GrowOnly, HotplugPolicy::GrowAndShrink]),
+        ),
+        ("offline", units_for(&[HotplugPolicy::GrowAndShrink])),
+    ] {
+        if !units.is_empty() {
+            rules += &format!(
+                "SUBSYSTEM==\"memory\", ACTION==\"{}\", RUN+=\"{} --no-block start{}\"\n",
+                action, SYSTEMCTL_COMMAND, units
+            );
+        }
+    }
+    rules
+}
+
+/// Write the memory hotplug udev rules for the configured devices, or remove them if there are none
+pub fn run_install_hotplug_rules(devices: &[Device]) -> Result<()> {
+    let rules = hotplug_rules(devices);
+    if rules.is_empty() {
+        return run_remove_hotplug_rules();
+    }
+
+    write_runtime_file(HOTPLUG_RULES_PATH, &rules)
+}
+
+pub fn run_remove_hotplug_rules() -> Result<()> {
+    remove_runtime_file(HOTPLUG_RULES_PATH)
+}
+
+/// The sysctl.d(5) fragment with the `[sysctl]` settings; empty if there are none, or no swap devices
+fn sysctl_fragment(config: &Config) -> String {
+    if !config.devices.iter().any(Device::is_swap) {
+        return String::new();
+    }
+    config
+        .sysctl
+        .iter()
+        .map(|setting| format!("{} = {}\n", setting.name, setting.value))
+        .collect()
+}
+
+/// `name = value` lines, as in sysctl.d(5), by name
+fn parse_sysctl_fragment(text: &str) -> BTreeMap<String, String> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with(['#', ';']))
+        .filter_map(|line| line.split_once('='))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .collect()
+}
+
+fn sysctl_path(proc_sys: &Path, name: &str) -> PathBuf {
+    proc_sys.join(name.replace('.', "/"))
+}
+
+/// `saved` with the current values of the settings that aren't in it yet;
+/// those already there were saved before an earlier `--install-sysctl` changed them
+fn save_sysctl(proc_sys: &Path, saved: &str, config: &Config) -> String {
+    let mut values = parse_sysctl_fragment(saved);
+    for setting in &config.sysctl {
+        if values.contains_key(&setting.name) {
+            continue;
+        }
+        match fs::read_to_string(sysctl_path(proc_sys, &setting.name)) {
+            Ok(value) => {
+                values.insert(setting.name.clone(), value.trim().to_string());
+            }
+            Err(e) => debug!("Not saving {}: {}", setting.name, e),
+        }
+    }
+    values
+        .iter()
+        .map(|(name, value)| format!("{} = {}\n", name, value))
+        .collect()
+}
+
+/// Write the values from `saved` back, carrying on if some fail
+fn restore_sysctl(proc_sys: &Path, saved: &str) -> Result<()> {
+    let values = parse_sysctl_fragment(saved);
+    let mut failed = 0;
+    for (name, value) in &values {
+        if let Err(e) = fs::write(sysctl_path(proc_sys, name), value) {
+            warn!("Failed to restore {} = {}: {}", name, value, e);
+            failed += 1;
+        }
+    }
+
+    match failed {
+        0 => Ok(()),
+        n => Err(anyhow!(
+            "Failed to restore {} of {} sysctl settings",
+            n,
+            values.len()
+        )),
+    }
+}
+
+/// Save the current values of the `[sysctl]` settings, write them to a sysctl.d(5) fragment and apply them,
+/// or, if there are none, do what `run_remove_sysctl()` does
+pub fn run_install_sysctl(config: &Config) -> Result<()> {
+    let fragment = sysctl_fragment(config);
+    if fragment.is_empty() {
+        return run_remove_sysctl();
+    }
+
+    let saved = match fs::read_to_string(SYSCTL_SAVED_PATH) {
+        Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+        r => r.with_context(|| format!("Failed to read {}", SYSCTL_SAVED_PATH))?,
+    };
+    write_runtime_file(
+        SYSCTL_SAVED_PATH,
+        &save_sysctl(Path::new("/proc/sys"), &saved, config),
+    )?;
+
+    write_runtime_file(SYSCTL_PATH, &fragment)?;
+    run_command(&mut Command::new(SYSTEMD_SYSCTL_COMMAND))
+}
+
+/// Remove the sysctl.d(5) fragment, and restore the values saved by `run_install_sysctl()`
+pub fn run_remove_sysctl() -> Result<()> {
+    remove_runtime_file(SYSCTL_PATH)?;
+
+    match fs::read_to_string(SYSCTL_SAVED_PATH) {
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        r => {
+            let saved = r.with_context(|| format!("Failed to read {}", SYSCTL_SAVED_PATH))?;
+            restore_sysctl(Path::new("/proc/sys"), &saved)?;
+            remove_runtime_file(SYSCTL_SAVED_PATH)
+        }
+    }
+}
+
+fn write_runtime_file(path: &str, contents: &str) -> Result<()> {
+    let path = Path::new(path);
+    fs::create_dir_all(path.parent().unwrap())
+        .and_then(|_| {
+            fs::write(
+                path,
+                format!(
+                    "# Automatically generated by {}\n\n{}",
+                    std::env::current_exe().unwrap().display(),
+                    contents
+                ),
+            )
+        })
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+fn remove_runtime_file(path: &str) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => {
+            Err(e).with_context(|| format!("Failed to remove {}", path))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Set up every device and start using it, as the generated units would, for systems without systemd
+pub fn run_setup_all(devices: &[Device]) -> Result<()> {
+    if devices.is_empty() {
+        info!("No devices configured.");
+        return Ok(());
+    }
+
+    generator::create_devices(devices)?;
+    generator::load_compressors(devices);
+
+    for_each_device(devices, "set up", |device| {
+        let initstate = Path::new("/sys/block").join(&device.name).join("initstate");
+        if matches!(fs::read_to_string(initstate), Ok(state) if state.trim() == "1") {
+            warn!(zram_device = device.name.as_str();
+                  "{}: already initialized, skipping.", device.name);
+            return Ok(());
+        }
+
+        setup_device(device)?;
+        activate_device(device)
+    })
+}
+
+/// Stop using every existing device, then reset and remove it; the reverse of `run_setup_all()`
+pub fn run_reset_all(devices: &[Device]) -> Result<()> {
+    for_each_device(devices, "reset", |device| {
+        run_device_reset(&device.name, true, true)
+    })
+}
+
+/// Reset and remove all zram devices we created that aren't configured, unless they're in use
+pub fn run_prune(devices: &[Device]) -> Result<()> {
+    let existing = fs::read_dir("/sys/block")
+        .context("Failed to list /sys/block")?
+        .map(|entry| Ok(entry?.file_name().to_string_lossy().into_owned()))
+        .collect::<Result<Vec<_>>>()
+        .context("Failed to list /sys/block")?;
+
+    let mut failed = 0;
+    let unconfigured = unconfigured_devices(existing, devices);
+    for device_name in &unconfigured {
+        if !is_created(device_name) {
+            debug!(zram_device = device_name.as_str(); "{}: not created by us, keeping.", device_name);
+            continue;
+        }
+
+        let holders = Path::new("/sys/block").join(device_name).join("holders");
+        if fs::read_dir(holders)
+            .ok()
+            .and_then(|mut h| h.next())
+            .is_some()
+        {
+            info!(zram_device = device_name.as_str(); "{}: held by another device, keeping.", device_name);
+            continue;
+        }
+        match DeviceUsers::find(device_name) {
+            Ok(users) if !users.is_empty() => {
+                info!(zram_device = device_name.as_str(); "{}: in use ({}), keeping.", device_name, users);
+                continue;
+            }
+            Ok(_) => {}
+            Err(e) => {
+                error!(zram_device = device_name.as_str(); "{}: {:#}", device_name, e);
+                failed += 1;
+                continue;
+            }
+        }
+
+        // Something started using it since, don't wait for it
+        match reset_device(device_name, true, 1) {
+            Ok(()) => {}
+            Err(e) if is_busy(&e) => {
+                info!(zram_device = device_name.as_str(); "{}: busy, keeping.", device_name);
+            }
+            Err(e) => {
+                error!(zram_device = device_name.as_str(); "{}: {:#}", device_name, e);
+                failed += 1;
+            }
+        }
+    }
+
+    match failed {
+        0 => Ok(()),
+        n => Err(anyhow!(
+            "Failed to prune {} of {} unconfigured devices",
+            n,
+            unconfigured.len()
+        )),
+    }
+}
+
+/// The zram devices among `existing` block devices that aren't in `devices`, in numerical order
+fn unconfigured_devices(existing: Vec<String>, devices: &[Device]) -> Vec<String> {
+    let mut ret: Vec<_> = existing
+        .into_iter()
+        .filter(|name| {
+            matches!(name.strip_prefix("zram"),
+                     Some(n) if !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()))
+        })
+        .filter(|name| !devices.iter().any(|d| &d.name == name))
+        .collect();
+    ret.sort_by_key(|name| name[4..].parse::<u64>().unwrap_or(u64::MAX));
+    ret
+}
+
+/// Run `f` for all devices, even if it fails for some
+fn for_each_device(
+    devices: &[Device],
+    action: &str,
+    mut f: impl FnMut(&Device) -> Result<()>,
+) -> Result<()> {
+    let mut failed = 0;
+    for device in devices {
+        if let Err(e) = f(device) {
+            error!(zram_device = device.name.as_str(); "{}: {:#}", device.name, e);
+            failed += 1;
+        }
+    }
+
+    match failed {
+        0 => Ok(()),
+        n => Err(anyhow!(
+            "Failed to {} {} of {} devices",
+            action,
+            n,
+            devices.len()
+        )),
+    }
+}
+
+/// `swapon` or `mount` the set-up device, like the generated .swap or .mount unit
+fn activate_device(device: &Device) -> Result<()> {
+    let dev_path = Path::new("/dev").join(&device.name);
+    let mut cmd;
+    if device.is_swap() {
+        // Short options only, as understood by both util-linux and busybox
+        cmd = Command::new("swapon");
+        cmd.arg(format!("-p{}", device.swap_priority));
+        for opt in device.options.split(',').filter(|o| !o.is_empty()) {
+            match opt.split_once('=') {
+                None if opt == "discard" => cmd.arg("-d"),
+                Some(("discard", policy)) => cmd.arg(format!("-d{}", policy)),
+                _ => {
+                    warn!(zram_device = device.name.as_str();
+                          "{}: swap option {} not supported without systemd, ignoring.", device.name, opt);
+                    continue;
+                }
+            };
+        }
+        cmd.arg(&dev_path);
+    } else {
+        let mount_point = device
+            .mount_point
+            .as_ref()
+            .ok_or_else(|| anyhow!("{} has no mount-point", device.name))?;
+        fs::create_dir_all(mount_point)
+            .with_context(|| format!("Failed to create {}", mount_point.display()))?;
+
+        cmd = Command::new("mount");
+        cmd.arg("-t").arg(device.effective_fs_type());
+        let options = device.mount_options();
+        if !options.is_empty() {
+            cmd.arg("-o").arg(&*options);
+        }
+        cmd.arg(&dev_path).arg(mount_point);
+    }
+
+    run_command(&mut cmd)
+}
+
+/// Where a device is in use: as swap, and mounted at some mount points
+struct DeviceUsers {
+    swap: bool,
+    mounts: Vec<PathBuf>,
+}
+
+impl DeviceUsers {
+    fn find(device_name: &str) -> Result<DeviceUsers> {
+        let dev_path = Path::new("/dev").join(device_name);
+        let swaps = fs::read_to_string("/proc/swaps").context("Failed to read /proc/swaps")?;
+
+        let dev_number = fs::read_to_string(Path::new("/sys/block").join(device_name).join("dev"))
+            .with_context(|| format!("Failed to read device number of {}", device_name))?;
+        let mountinfo = fs::read_to_string("/proc/self/mountinfo")
+            .context("Failed to read /proc/self/mountinfo")?;
+
+        Ok(DeviceUsers {
+            swap: swaps_contain(&swaps, &dev_path),
+            mounts: mounts_of(&mountinfo, dev_number.trim()),
+        })
+    }
+
+    fn is_empty(&self) -> bool {
+        !self.swap && self.mounts.is_empty()
+    }
+
+    /// `swapoff` and `umount` the device, wherever it's in use
+    fn deactivate(&self, device_name: &str) -> Result<()> {
+        if self.swap {
+            info!(zram_device = device_name; "{}: disabling swap", device_name);
+            run_command(Command::new("swapoff").arg(Path::new("/dev").join(device_name)))?;
+        }
+
+        // Innermost first
+        for mount_point in self.mounts.iter().rev() {
+            info!(zram_device = device_name; "{}: unmounting {}", device_name, mount_point.display());
+            run_command(Command::new("umount").arg(mount_point))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for DeviceUsers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut sep = "";
+        if self.swap {
+            f.write_str("active swap")?;
+            sep = ", ";
+        }
+        for mount_point in &self.mounts {
+            write!(f, "{}mounted at {}", sep, mount_point.display())?;
+            sep = ", ";
+        }
+        Ok(())
+    }
+}
+
+fn run_command(cmd: &mut Command) -> Result<()> {
+    let status = cmd
+        .status()
+        .with_context(|| format!("Failed to run {:?}", cmd))?;
+    match status.code() {
+        Some(0) => Ok(()),
+        Some(code) => Err(anyhow!("{:?} failed with exit code {}", cmd, code)),
+        None => Err(anyhow!(
+            "{:?} terminated by signal {}",
+            cmd,
+            status
+                .signal()
+                .expect("on unix, status.code() is None iff status.signal() isn't")
+        )),
+    }
+}
+
+/// Whether the first column of /proc/swaps lists `dev_path`
+fn swaps_contain(swaps: &str, dev_path: &Path) -> bool {
+    swaps
+        .lines()
+        .skip(1)
+        .filter_map(|l| l.split_whitespace().next())
+        .any(|file| Path::new(file) == dev_path)
+}
+
+/// Mount points of the device numbered `dev_number` ("major:minor") in /proc/self/mountinfo, in order
+fn mounts_of(mountinfo: &str, dev_number: &str) -> Vec<PathBuf> {
+    mountinfo
+        .lines()
+        .map(|l| l.split(' ').collect::<Vec<_>>())
+        .filter(|fields| fields.len() > 4 && fields[2] == dev_number)
+        .map(|fields| PathBuf::from(unescape_mountinfo(fields[4])))
+        .collect()
+}
+
+/// Undo the octal escaping of spaces, tabs, newlines, and backslashes in /proc/self/mountinfo
+fn unescape_mountinfo(field: &str) -> String {
+    let mut ret = String::with_capacity(field.len());
+    let mut rest = field;
+    while let Some(idx) = rest.find('\\') {
+        ret.push_str(&rest[..idx]);
+        let escape = rest.get(idx + 1..idx + 4);
+        match escape.and_then(|e| u8::from_str_radix(e, 8).ok()) {
+            Some(byte) => {
+                ret.push(byte as char);
+                rest = &rest[idx + 4..];
+            }
+            None => {
+                ret.push('\\');
+                rest = &rest[idx + 1..];
+            }
+        }
+    }
+    ret.push_str(rest);
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Sysctl;
+
+    #[test]
+    fn test_swaps_contain() {
+        let swaps = "\
+Filename\t\t\t\tType\t\tSize\t\tUsed\t\tPriority
+/dev/zram0                              partition\t8388604\t\t0\t\t100
+/dev/dm-1                               partition\t8388604\t\t0\t\t-2
+";
+        assert!(swaps_contain(swaps, Path::new("/dev/zram0")));
+        assert!(!swaps_contain(swaps, Path::new("/dev/zram1")));
+        assert!(!swaps_contain(swaps, Path::new("Filename")));
+    }
+
+    #[test]
+    fn test_mounts_of() {
+        let mountinfo = "\
+22 1 253:1 / / rw,relatime shared:1 - ext4 /dev/mapper/root rw
+80 22 252:1 / /var/tmp rw,relatime shared:40 - ext4 /dev/zram1 rw,discard
+81 80 252:10 / /var/tmp/a\\040b rw,relatime shared:41 - ext2 /dev/zram10 rw
+82 22 252:1 / /mnt/again rw,relatime shared:40 - ext4 /dev/zram1 rw,discard
+";
+        assert_eq!(
+            mounts_of(mountinfo, "252:1"),
+            [PathBuf::from("/var/tmp"), PathBuf::from("/mnt/again")]
+        );
+        assert_eq!(
+            mounts_of(mountinfo, "252:10"),
+            [PathBuf::from("/var/tmp/a b")]
+        );
+        assert!(mounts_of(mountinfo, "252:0").is_empty());
+    }
+
+    #[test]
+    fn test_unconfigured_devices() {
+        let existing = [
+            "zram10", "sda", "zram2", "zram0", "zram", "zramx", "loop0", "zram1",
+        ];
+        let devices = [
+            Device::new("zram0".to_string()),
+            Device::new("zram2".to_string()),
+        ];
+        assert_eq!(
+            unconfigured_devices(existing.iter().map(|s| s.to_string()).collect(), &devices),
+            ["zram1", "zram10"]
+        );
+    }
+
+    #[test]
+    fn test_hotplug_rules() {
+        let device = |name: &str, memory_hotplug, mount_point: Option<&str>| Device {
+            memory_hotplug,
+            mount_point: mount_point.map(PathBuf::from),
+            ..Device::new(name.to_string())
+        };
+
+        assert_eq!(
+            hotplug_rules(&[device("zram0", HotplugPolicy::Ignore, None)]),
+            ""
+        );
+        assert_eq!(
+            hotplug_rules(&[
+                device("zram2", HotplugPolicy::GrowAndShrink, None),
+                device("zram0", HotplugPolicy::GrowOnly, None),
+                device("zram1", HotplugPolicy::GrowOnly, Some("/var/tmp")),
+            ]),
+            format!(
+                "\
+SUBSYSTEM==\"memory\", ACTION==\"online\", RUN+=\"{0} --no-block start systemd-zram-resize@zram0.service systemd-zram-resize@zram2.service\"
+SUBSYSTEM==\"memory\", ACTION==\"offline\", RUN+=\"{0} --no-block start systemd-zram-resize@zram2.service\"
+",
+                SYSTEMCTL_COMMAND
+            )
+        );
+    }
+
+    #[test]
+    fn test_sysctl_fragment() {
+        let setting = |name: &str, value: &str| Sysctl {
+            name: name.to_string(),
+            value: value.to_string(),
+            origin: None,
+        };
+        let mut config = Config {
+            devices: vec![Device::new("zram0".to_string())],
+            sysctl: vec![
+                setting("vm.page-cluster", "0"),
+                setting("vm.swappiness", "180"),
+            ],
+            virtualization: Default::default(),
+        };
+        assert_eq!(
+            sysctl_fragment(&config),
+            "vm.page-cluster = 0\nvm.swappiness = 180\n"
+        );
+
+        config.devices[0].mount_point = Some(PathBuf::from("/var/tmp"));
+        assert_eq!(sysctl_fragment(&config), "");
+    }
+
+    #[test]
+    fn test_restart_swap_unit() {
+        let mut issued = vec![];
+        restart_swap_unit("dev-zram0.swap", &mut |cmd| {
+            issued.push(
+                std::iter::once(cmd.get_program())
+                    .chain(cmd.get_args())
+                    .map(|arg| arg.to_string_lossy().into_owned())
+                    .collect::<Vec<_>>()
+                    .join(" "),
+            );
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(
+            issued,
+            [
+                format!("{} stop dev-zram0.swap", SYSTEMCTL_COMMAND),
+                format!("{} start dev-zram0.swap", SYSTEMCTL_COMMAND),
+            ]
+        );
+
+        // Nothing is started if stopping fails
+        let mut issued = 0;
+        assert!(restart_swap_unit("dev-zram0.swap", &mut |_| {
+            issued += 1;
+            Err(anyhow!("failed"))
+        })
+        .is_err());
+        assert_eq!(issued, 1);
+    }
+
+    #[test]
+    fn test_save_restore_sysctl() {
+        let proc_sys = tempfile::TempDir::new().unwrap();
+        let proc_sys = proc_sys.path();
+        fs::create_dir(proc_sys.join("vm")).unwrap();
+        fs::write(proc_sys.join("vm/swappiness"), "60\n").unwrap();
+        fs::write(proc_sys.join("vm/page-cluster"), "3\n").unwrap();
+
+        let setting = |name: &str, value: &str| Sysctl {
+            name: name.to_string(),
+            value: value.to_string(),
+            origin: None,
+        };
+        let config = Config {
+            devices: vec![Device::new("zram0".to_string())],
+            sysctl: vec![
+                setting("vm.page-cluster", "0"),
+                setting("vm.swappiness", "180"),
+                setting("vm.missing", "1"),
+            ],
+            virtualization: Default::default(),
+        };
+
+        // Values saved by an earlier run are kept, not replaced by the ones it set
+        let saved = save_sysctl(
+            proc_sys,
+            "# Automatically generated\n\nvm.swappiness = 30\n",
+            &config,
+        );
+        assert_eq!(saved, "vm.page-cluster = 3\nvm.swappiness = 30\n");
+
+        fs::write(proc_sys.join("vm/swappiness"), "180\n").unwrap();
+        fs::write(proc_sys.join("vm/page-cluster"), "0\n").unwrap();
+        restore_sysctl(proc_sys, &saved).unwrap();
+        assert_eq!(
+            fs::read_to_string(proc_sys.join("vm/swappiness")).unwrap(),
+            "30"
+        );
+        assert_eq!(
+            fs::read_to_string(proc_sys.join("vm/page-cluster")).unwrap(),
+            "3"
+        );
+
+        assert!(restore_sysctl(proc_sys, "vm.missing/nested = 1\n").is_err());
+    }
+
+    #[test]
+    fn test_plan_resize() {
+        let unused = DeviceUsers {
+            swap: false,
+            mounts: vec![],
+        };
+        let swap = DeviceUsers {
+            swap: true,
+            mounts: vec![],
+        };
+        let mounted = DeviceUsers {
+            swap: false,
+            mounts: vec![PathBuf::from("/var/tmp")],
+        };
+
+        assert_eq!(plan_resize(1024, 1024, false, &swap), ResizePlan::Keep);
+        assert_eq!(plan_resize(1024, 1024, true, &mounted), ResizePlan::Keep);
+        assert_eq!(plan_resize(1024, 512, true, &swap), ResizePlan::KeepLarger);
+        assert_eq!(
+            plan_resize(1024, 512, true, &unused),
+            ResizePlan::KeepLarger
+        );
+        assert_eq!(
+            plan_resize(512, 1024, true, &swap),
+            ResizePlan::ThroughTemporary
+        );
+        assert_eq!(
+            plan_resize(1024, 512, false, &swap),
+            ResizePlan::ThroughTemporary
+        );
+        assert_eq!(plan_resize(512, 1024, true, &unused), ResizePlan::InPlace);
+        assert_eq!(plan_resize(1024, 512, false, &unused), ResizePlan::InPlace);
+        assert_eq!(plan_resize(512, 1024, false, &mounted), ResizePlan::Mounted);
+    }
+
+    #[test]
+    fn test_is_busy() {
+        let busy: Result<()> = Err(io::Error::from_raw_os_error(EBUSY).into());
+        assert!(is_busy(
+            &busy.context("Failed to write 1 to reset").unwrap_err()
+        ));
+        let denied: Result<()> = Err(io::Error::from(ErrorKind::PermissionDenied).into());
+        assert!(!is_busy(
+            &denied.context("Failed to write 1 to reset").unwrap_err()
+        ));
+        assert!(!is_busy(&anyhow!("zram0 is in use")));
+    }
+
+    #[test]
+    fn test_device_users_display() {
+        let users = DeviceUsers {
+            swap: true,
+            mounts: vec![],
+        };
+        assert_eq!(users.to_string(), "active swap");
+        let users = DeviceUsers {
+            swap: true,
+            mounts: vec!["/tmp".into(), "/mnt/a b".into()],
+        };
+        assert_eq!(
+            users.to_string(),
+            "active swap, mounted at /tmp, mounted at /mnt/a b"
+        );
+        let users = DeviceUsers {
+            swap: false,
+            mounts: vec!["/tmp".into()],
+        };
+        assert_eq!(users.to_string(), "mounted at /tmp");
+    }
+
+    #[test]
+    fn test_unescape_mountinfo() {
+        assert_eq!(unescape_mountinfo("/a\\040b\\011c"), "/a b\tc");
+        assert_eq!(unescape_mountinfo("/a\\134b"), "/a\\b");
+        assert_eq!(unescape_mountinfo("/a\\b"), "/a\\b");
+        assert_eq!(unescape_mountinfo("/a\\"), "/a\\");
+    }
+}
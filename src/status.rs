@@ -0,0 +1,436 @@
+/* SPDX-License-Identifier: MIT */
+
+use anyhow::{anyhow, Context, Result};
+use std::fmt;
+use std::fs;
+use std::io::{self, ErrorKind, Write};
+use std::path::Path;
+
+/// Size of the pages `bd_stat` counts in, regardless of `PAGE_SIZE`
+const BD_STAT_UNIT: u64 = 4096;
+
+/// Statistics of a single zram device, as read from `/sys/block/zramN/`
+#[derive(Debug, Default, PartialEq)]
+pub struct DeviceStatus {
+    pub name: String,
+    pub disksize: u64,
+    /// the one in [brackets] in `comp_algorithm`
+    pub comp_algorithm: Option<String>,
+
+    // mm_stat, in bytes except for the page counts
+    pub orig_data_size: u64,
+    pub compr_data_size: u64,
+    pub mem_used_total: u64,
+    pub mem_limit: u64,
+    pub mem_used_max: u64,
+    pub same_pages: u64,
+    pub pages_compacted: u64,
+    pub huge_pages: u64,
+
+    // io_stat
+    pub failed_reads: u64,
+    pub failed_writes: u64,
+    pub invalid_io: u64,
+    pub notify_free: u64,
+
+    /// `None` without a backing device (or without `CONFIG_ZRAM_WRITEBACK`)
+    pub writeback: Option<WritebackStatus>,
+}
+
+/// `backing_dev` and `bd_stat`, the latter converted to bytes
+#[derive(Debug, Default, PartialEq)]
+pub struct WritebackStatus {
+    pub backing_dev: String,
+    pub stored: u64,
+    pub reads: u64,
+    pub writes: u64,
+}
+
+impl DeviceStatus {
+    pub fn compression_ratio(&self) -> Option<f64> {
+        match self.compr_data_size {
+            0 => None,
+            compr => Some(self.orig_data_size as f64 / compr as f64),
+        }
+    }
+}
+
+fn read_attribute(device_sysfs_path: &Path, attribute: &str) -> Result<Option<String>> {
+    let path = device_sysfs_path.join(attribute);
+    match fs::read_to_string(&path) {
+        Ok(val) => Ok(Some(val.trim_end().to_string())),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    }
+}
+
+fn parse_stat(attribute: &str, val: Option<&str>, fields: &mut [&mut u64]) -> Result<()> {
+    if let Some(val) = val {
+        for (field, v) in fields.iter_mut().zip(val.split_whitespace()) {
+            **field = v
+                .parse()
+                .with_context(|| format!("Failed to parse {} field \"{}\"", attribute, v))?;
+        }
+    }
+    Ok(())
+}
+
+fn parse_comp_algorithm(val: &str) -> Option<&str> {
+    val.split_whitespace()
+        .find(|alg| alg.starts_with('[') && alg.ends_with(']'))
+        .map(|alg| &alg[1..alg.len() - 1])
+}
+
+pub fn read_device_status(root: &Path, device_name: &str) -> Result<DeviceStatus> {
+    let device_sysfs_path = root.join("sys/block").join(device_name);
+    if !device_sysfs_path.exists() {
+        return Err(anyhow!("Device {} not found", device_name));
+    }
+
+    let mut status = DeviceStatus {
+        name: device_name.to_string(),
+        ..Default::default()
+    };
+
+    if let Some(disksize) = read_attribute(&device_sysfs_path, "disksize")? {
+        status.disksize = disksize
+            .parse()
+            .with_context(|| format!("Failed to parse disksize \"{}\"", disksize))?;
+    }
+    status.comp_algorithm = read_attribute(&device_sysfs_path, "comp_algorithm")?
+        .as_deref()
+        .and_then(parse_comp_algorithm)
+        .map(str::to_string);
+
+    parse_stat(
+        "mm_stat",
+        read_attribute(&device_sysfs_path, "mm_stat")?.as_deref(),
+        &mut [
+            &mut status.orig_data_size,
+            &mut status.compr_data_size,
+            &mut status.mem_used_total,
+            &mut status.mem_limit,
+            &mut status.mem_used_max,
+            &mut status.same_pages,
+            &mut status.pages_compacted,
+            &mut status.huge_pages,
+        ],
+    )?;
+    parse_stat(
+        "io_stat",
+        read_attribute(&device_sysfs_path, "io_stat")?.as_deref(),
+        &mut [
+            &mut status.failed_reads,
+            &mut status.failed_writes,
+            &mut status.invalid_io,
+            &mut status.notify_free,
+        ],
+    )?;
+
+    status.writeback = match read_attribute(&device_sysfs_path, "backing_dev")? {
+        Some(backing_dev) if backing_dev != "none" => {
+            let mut wb = WritebackStatus {
+                backing_dev,
+                ..Default::default()
+            };
+            parse_stat(
+                "bd_stat",
+                read_attribute(&device_sysfs_path, "bd_stat")?.as_deref(),
+                &mut [&mut wb.stored, &mut wb.reads, &mut wb.writes],
+            )?;
+            wb.stored *= BD_STAT_UNIT;
+            wb.reads *= BD_STAT_UNIT;
+            wb.writes *= BD_STAT_UNIT;
+            Some(wb)
+        }
+        _ => None,
+    };
+
+    Ok(status)
+}
+
+pub fn read_all_device_statuses(root: &Path) -> Result<Vec<DeviceStatus>> {
+    let sys_block = root.join("sys/block");
+    let mut names: Vec<(u64, String)> = fs::read_dir(&sys_block)
+        .with_context(|| format!("Failed to list {}", sys_block.display()))?
+        .flatten()
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter_map(|name| match name.strip_prefix("zram").map(str::parse) {
+            Some(Ok(num)) => Some((num, name)),
+            _ => None,
+        })
+        .collect();
+    names.sort();
+
+    names
+        .into_iter()
+        .map(|(_, name)| read_device_status(root, &name))
+        .collect()
+}
+
+/// Binary-prefixed, like zramctl(8)
+struct HumanSize(u64);
+impl fmt::Display for HumanSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut val = self.0 as f64;
+        for unit in ["B", "K", "M", "G", "T"] {
+            if val < 1024. || unit == "T" {
+                return match unit {
+                    "B" => write!(f, "{}B", self.0),
+                    _ if val.fract() == 0. => write!(f, "{}{}", val, unit),
+                    _ => write!(f, "{:.1}{}", val, unit),
+                };
+            }
+            val /= 1024.;
+        }
+        unreachable!()
+    }
+}
+
+pub fn write_table(out: &mut dyn Write, statuses: &[DeviceStatus]) -> io::Result<()> {
+    let rows: Vec<[String; 10]> = statuses
+        .iter()
+        .map(|st| {
+            let wb = |f: fn(&WritebackStatus) -> u64| {
+                st.writeback
+                    .as_ref()
+                    .map(|wb| HumanSize(f(wb)).to_string())
+                    .unwrap_or_else(|| "-".to_string())
+            };
+            [
+                st.name.clone(),
+                st.comp_algorithm.clone().unwrap_or_else(|| "-".to_string()),
+                HumanSize(st.disksize).to_string(),
+                HumanSize(st.orig_data_size).to_string(),
+                HumanSize(st.compr_data_size).to_string(),
+                HumanSize(st.mem_used_total).to_string(),
+                st.compression_ratio()
+                    .map(|r| format!("{:.2}", r))
+                    .unwrap_or_else(|| "-".to_string()),
+                wb(|wb| wb.stored),
+                wb(|wb| wb.reads),
+                wb(|wb| wb.writes),
+            ]
+        })
+        .collect();
+
+    let header = [
+        "NAME",
+        "ALGORITHM",
+        "DISKSIZE",
+        "DATA",
+        "COMPR",
+        "TOTAL",
+        "RATIO",
+        "WB-STORED",
+        "WB-READ",
+        "WB-WRITTEN",
+    ];
+    let mut widths = header.map(str::len);
+    for row in &rows {
+        for (w, col) in widths.iter_mut().zip(row) {
+            *w = (*w).max(col.len());
+        }
+    }
+
+    let mut write_row = |row: &mut dyn Iterator<Item = &str>| -> io::Result<()> {
+        let line = row
+            .zip(widths.iter())
+            .enumerate()
+            .map(|(i, (col, w))| match i {
+                0 | 1 => format!("{:<1$}", col, w),
+                _ => format!("{:>1$}", col, w),
+            })
+            .collect::<Vec<_>>()
+            .join(" ");
+        writeln!(out, "{}", line.trim_end())
+    };
+
+    write_row(&mut header.iter().copied())?;
+    for row in &rows {
+        write_row(&mut row.iter().map(String::as_str))?;
+    }
+    Ok(())
+}
+
+struct JsonStr<'a>(&'a str);
+impl fmt::Display for JsonStr<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("\"")?;
+        for c in self.0.chars() {
+            match c {
+                '"' => f.write_str("\\\"")?,
+                '\\' => f.write_str("\\\\")?,
+                c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+                c => write!(f, "{}", c)?,
+            }
+        }
+        f.write_str("\"")
+    }
+}
+
+pub fn write_json(out: &mut dyn Write, statuses: &[DeviceStatus]) -> io::Result<()> {
+    write!(out, "[")?;
+    for (i, st) in statuses.iter().enumerate() {
+        if i != 0 {
+            write!(out, ",")?;
+        }
+        write!(out, "{{\"name\":{},\"algorithm\":", JsonStr(&st.name))?;
+        match st.comp_algorithm.as_deref() {
+            Some(alg) => write!(out, "{}", JsonStr(alg))?,
+            None => write!(out, "null")?,
+        }
+        write!(
+            out,
+            ",\"disksize\":{},\"orig_data_size\":{},\"compr_data_size\":{},\"mem_used_total\":{},\
+             \"mem_limit\":{},\"mem_used_max\":{},\"same_pages\":{},\"pages_compacted\":{},\
+             \"huge_pages\":{},\"compression_ratio\":",
+            st.disksize,
+            st.orig_data_size,
+            st.compr_data_size,
+            st.mem_used_total,
+            st.mem_limit,
+            st.mem_used_max,
+            st.same_pages,
+            st.pages_compacted,
+            st.huge_pages,
+        )?;
+        match st.compression_ratio() {
+            Some(ratio) => write!(out, "{}", ratio)?,
+            None => write!(out, "null")?,
+        }
+        write!(
+            out,
+            ",\"failed_reads\":{},\"failed_writes\":{},\"invalid_io\":{},\"notify_free\":{},\
+             \"writeback\":",
+            st.failed_reads, st.failed_writes, st.invalid_io, st.notify_free,
+        )?;
+        match st.writeback.as_ref() {
+            Some(wb) => write!(
+                out,
+                "{{\"backing_dev\":{},\"stored\":{},\"reads\":{},\"writes\":{}}}",
+                JsonStr(&wb.backing_dev),
+                wb.stored,
+                wb.reads,
+                wb.writes
+            )?,
+            None => write!(out, "null")?,
+        }
+        write!(out, "}}")?;
+    }
+    writeln!(out, "]")
+}
+
+pub fn run_status(root: &Path, device_name: Option<&str>, json: bool) -> Result<()> {
+    let statuses = match device_name {
+        Some(device_name) => vec![read_device_status(root, device_name)?],
+        None => read_all_device_statuses(root)?,
+    };
+
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    match json {
+        true => write_json(&mut stdout, &statuses)?,
+        false => write_table(&mut stdout, &statuses)?,
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fake_device(root: &Path, name: &str, attrs: &[(&str, &str)]) {
+        let dir = root.join("sys/block").join(name);
+        fs::create_dir_all(&dir).unwrap();
+        for (attr, val) in attrs {
+            fs::write(dir.join(attr), val).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_parse_comp_algorithm() {
+        assert_eq!(
+            parse_comp_algorithm("lzo lzo-rle [lz4] zstd\n"),
+            Some("lz4")
+        );
+        assert_eq!(parse_comp_algorithm("lzo lzo-rle lz4"), None);
+    }
+
+    #[test]
+    fn test_read_device_status() {
+        let rootdir = tempfile::TempDir::new().unwrap();
+        let root = rootdir.path();
+        fake_device(
+            root,
+            "zram1",
+            &[
+                ("disksize", "4294967296\n"),
+                ("comp_algorithm", "lzo lzo-rle lz4 [zstd]\n"),
+                (
+                    "mm_stat",
+                    "   9437184   3145728   3670016        0   3670016      120        0       12\n",
+                ),
+                ("io_stat", "       0        0        0       7\n"),
+                ("backing_dev", "/dev/sdb1\n"),
+                ("bd_stat", "       2       0       5\n"),
+            ],
+        );
+        fake_device(root, "zram0", &[("disksize", "0\n")]);
+        fake_device(root, "sda", &[]);
+
+        let statuses = read_all_device_statuses(root).unwrap();
+        assert_eq!(statuses.len(), 2);
+        assert_eq!(statuses[0].name, "zram0");
+        assert_eq!(statuses[0].writeback, None);
+        assert_eq!(statuses[0].compression_ratio(), None);
+
+        let st = &statuses[1];
+        assert_eq!(st.disksize, 4 * 1024 * 1024 * 1024);
+        assert_eq!(st.comp_algorithm.as_deref(), Some("zstd"));
+        assert_eq!(st.orig_data_size, 9 * 1024 * 1024);
+        assert_eq!(st.compr_data_size, 3 * 1024 * 1024);
+        assert_eq!(st.huge_pages, 12);
+        assert_eq!(st.notify_free, 7);
+        assert_eq!(st.compression_ratio(), Some(3.));
+        assert_eq!(
+            st.writeback,
+            Some(WritebackStatus {
+                backing_dev: "/dev/sdb1".to_string(),
+                stored: 2 * 4096,
+                reads: 0,
+                writes: 5 * 4096,
+            })
+        );
+
+        let mut table = Vec::new();
+        write_table(&mut table, &statuses).unwrap();
+        assert_eq!(
+            String::from_utf8(table).unwrap(),
+            "\
+NAME  ALGORITHM DISKSIZE DATA COMPR TOTAL RATIO WB-STORED WB-READ WB-WRITTEN
+zram0 -               0B   0B    0B    0B     -         -       -          -
+zram1 zstd            4G   9M    3M  3.5M  3.00        8K      0B        20K
+"
+        );
+
+        let mut json = Vec::new();
+        write_json(&mut json, &statuses[1..]).unwrap();
+        assert_eq!(
+            String::from_utf8(json).unwrap(),
+            "[{\"name\":\"zram1\",\"algorithm\":\"zstd\",\"disksize\":4294967296,\
+             \"orig_data_size\":9437184,\"compr_data_size\":3145728,\"mem_used_total\":3670016,\
+             \"mem_limit\":0,\"mem_used_max\":3670016,\"same_pages\":120,\"pages_compacted\":0,\
+             \"huge_pages\":12,\"compression_ratio\":3,\"failed_reads\":0,\"failed_writes\":0,\
+             \"invalid_io\":0,\"notify_free\":7,\"writeback\":{\"backing_dev\":\"/dev/sdb1\",\
+             \"stored\":8192,\"reads\":0,\"writes\":20480}}]\n"
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Device zram3 not found")]
+    fn test_read_device_status_missing() {
+        let rootdir = tempfile::TempDir::new().unwrap();
+        read_device_status(rootdir.path(), "zram3").unwrap();
+    }
+}
@@ -6,7 +6,7 @@ use anyhow::Result;
 use fs_extra::dir::{copy, CopyOptions};
 use std::fs;
 use std::io::{self, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 use tempfile::TempDir;
 
@@ -33,7 +33,7 @@ fn prepare_directory(srcroot: &Path) -> Result<TempDir> {
     let root = rootdir.path();
 
     let opts = CopyOptions::new();
-    for p in ["etc", "usr", "proc"]
+    for p in ["etc", "usr", "proc", "sys"]
         .iter()
         .map(|p| srcroot.join(p))
         .filter(|p| p.exists())
@@ -49,7 +49,7 @@ fn prepare_directory(srcroot: &Path) -> Result<TempDir> {
 
 fn test_generation(path: &str) -> Result<Vec<config::Device>> {
     let srcroot = Path::new(path);
-    let rootdir = prepare_directory(&srcroot)?;
+    let rootdir = prepare_directory(srcroot)?;
     let root = rootdir.path();
 
     let kernel_override = match config::kernel_zram_option(root) {
@@ -59,10 +59,15 @@ fn test_generation(path: &str) -> Result<Vec<config::Device>> {
         }
         _ => false,
     };
-    let devices = config::read_all_devices(root, kernel_override)?;
+    // What systemd-detect-virt would print, if the test case isn't "none"
+    let virtualization = match fs::read_to_string(srcroot.join("systemd-detect-virt")) {
+        Ok(output) => config::Virtualization::fake(&output),
+        Err(_) => config::Virtualization::fake("none"),
+    };
+    let config = config::read_config(root, kernel_override, virtualization)?;
 
     let output_directory = root.join("run/units");
-    generator::run_generator(&devices, &output_directory, true)?;
+    generator::run_generator(&config, &output_directory, true)?;
 
     // Compare output directory to expected value.
     // ExecStart lines include the full path to the generating binary,
@@ -81,7 +86,7 @@ fn test_generation(path: &str) -> Result<Vec<config::Device>> {
     }
     assert!(diff.status.success());
 
-    Ok(devices)
+    Ok(config.devices)
 }
 
 fn z_s_name(zram_size: &(String, fasteval::ExpressionI, fasteval::Slab)) -> &str {
@@ -107,7 +112,7 @@ fn test_02_zstd() {
     assert!(d.is_swap());
     assert_eq!(d.host_memory_limit_mb, Some(2050));
     assert_eq!(d.zram_size.as_ref().map(z_s_name), Some("ram * 0.75"));
-    assert_eq!(d.compression_algorithm.as_ref().unwrap(), "zstd");
+    assert_eq!(d.compression_algorithms[0].name, "zstd");
     assert_eq!(d.options, "discard");
 }
 
@@ -130,11 +135,25 @@ fn test_04_dropins() {
                 assert_eq!(d.host_memory_limit_mb, Some(1235));
                 assert_eq!(d.zram_size.as_ref().map(z_s_name), None);
                 assert_eq!(d.options, "discard");
+
+                let origin = &d.origins["host-memory-limit"];
+                assert!(origin
+                    .path
+                    .ends_with("usr/lib/systemd/zram-generator.conf.d/02-drop2.conf"));
+                assert_eq!(origin.line, 2);
+                assert_eq!(d.origins.len(), 1);
             }
             "zram2" => {
                 assert_eq!(d.host_memory_limit_mb, None);
                 assert_eq!(d.zram_size.as_ref().map(z_s_name), Some("ram*0.8"));
                 assert_eq!(d.options, "");
+
+                let origin = &d.origins["options"];
+                assert!(origin
+                    .path
+                    .ends_with("etc/systemd/zram-generator.conf.d/04-drop4.conf"));
+                assert_eq!(origin.line, 4);
+                assert_eq!(d.origins.len(), 3);
             }
             _ => panic!("Unexpected device {}", d),
         }
@@ -242,7 +261,7 @@ fn test_09_zram_size() {
         d.zram_size.as_ref().map(z_s_name),
         Some("min(0.75 * ram, 6000)")
     );
-    assert_eq!(d.compression_algorithm.as_ref().unwrap(), "zstd");
+    assert_eq!(d.compression_algorithms[0].name, "zstd");
 }
 
 #[test]
@@ -266,7 +285,10 @@ fn test_10_example() {
                     d.zram_size.as_ref().map(z_s_name),
                     Some("min(ram / 10, 2048)")
                 );
-                assert_eq!(d.compression_algorithm.as_deref(), Some("lzo-rle"));
+                assert_eq!(
+                    d.compression_algorithms.first().map(|a| &a.name[..]),
+                    Some("lzo-rle")
+                );
                 assert_eq!(d.options, "");
             }
             "zram1" => {
@@ -303,3 +325,453 @@ fn test_11_obsolete() {
         }
     }
 }
+
+#[test]
+fn test_12_recompression() {
+    let devices = test_generation("tests/12-recompression").unwrap();
+    assert_eq!(devices.len(), 2);
+
+    for d in &devices {
+        assert!(d.is_swap());
+        match d.name.as_str() {
+            "zram0" => {
+                assert_eq!(
+                    d.compression_algorithms.first().map(|a| &a.name[..]),
+                    Some("lz4")
+                );
+                assert_eq!(
+                    d.recompression_algorithms
+                        .iter()
+                        .map(|alg| alg.to_string())
+                        .collect::<Vec<_>>(),
+                    ["zstd", "deflate(priority=3)"]
+                );
+                assert_eq!(d.recompression_interval, Some(90 * 60));
+                assert_eq!(d.recompression_type, "huge_idle");
+                assert_eq!(d.recompression_threshold, Some(1024));
+            }
+            "zram1" => {
+                assert_eq!(d.recompression_algorithms.len(), 1);
+                assert_eq!(d.recompression_interval, None);
+                assert_eq!(d.recompression_type, "idle");
+                assert_eq!(d.recompression_threshold, None);
+            }
+            _ => panic!("Unexpected device {}", d),
+        }
+    }
+}
+
+#[test]
+fn test_13_writeback() {
+    let devices = test_generation("tests/13-writeback").unwrap();
+    assert_eq!(devices.len(), 2);
+
+    for d in &devices {
+        assert!(d.is_swap());
+        match d.name.as_str() {
+            "zram0" => {
+                assert_eq!(
+                    d.writeback_dev.as_deref(),
+                    Some(Path::new("/dev/disk/by-partlabel/zram-writeback"))
+                );
+                assert_eq!(d.writeback_interval, Some(24 * 60 * 60));
+                assert_eq!(d.writeback_idle_age, Some(12 * 60 * 60));
+                assert_eq!(d.writeback_limit_mb, Some(512));
+            }
+            "zram1" => {
+                assert_eq!(d.writeback_dev, None);
+                assert_eq!(d.writeback_interval, Some(24 * 60 * 60));
+                assert_eq!(d.writeback_idle_age, None);
+                assert_eq!(d.writeback_limit_mb, None);
+            }
+            _ => panic!("Unexpected device {}", d),
+        }
+    }
+}
+
+#[test]
+fn test_14_host_variables() {
+    let devices = test_generation("tests/14-host-variables").unwrap();
+    assert_eq!(devices.len(), 2);
+
+    for d in &devices {
+        match d.name.as_str() {
+            "zram0" => {
+                // 5 CPUs online
+                assert_eq!(d.disksize, 5 * 256 * 1024 * 1024);
+                assert_eq!(d.mem_limit_bytes, 0);
+            }
+            "zram1" => {
+                // (4096MB available - 256 * 2MB huge pages) / 2 nodes
+                assert_eq!(d.disksize, 1792 * 1024 * 1024);
+                // 4000MB on node1 / 4 + 2048MB swap / 8
+                assert_eq!(d.mem_limit_bytes, 1256 * 1024 * 1024);
+            }
+            _ => panic!("Unexpected device {}", d),
+        }
+    }
+}
+
+#[test]
+fn test_15_templates() {
+    let devices = test_generation("tests/15-templates").unwrap();
+    assert_eq!(devices.len(), 4);
+
+    for d in &devices {
+        assert!(d.is_swap());
+        assert_eq!(d.options, "discard");
+        match d.name.as_str() {
+            "zram0" | "zram1" | "zram3" => {
+                assert_eq!(d.zram_size.as_ref().map(z_s_name), Some("ram / 4 / count"));
+                // 782MB / 4 / 4 devices
+                assert_eq!(d.disksize, 51249152);
+                assert_eq!(
+                    d.compression_algorithms.first().map(|a| &a.name[..]),
+                    Some("zstd")
+                );
+            }
+            "zram2" => {
+                assert_eq!(d.disksize, 1002 * 1024 * 1024);
+                assert_eq!(
+                    d.compression_algorithms.first().map(|a| &a.name[..]),
+                    Some("lz4")
+                );
+                assert_eq!(d.origins["compression-algorithm"].line, 3);
+            }
+            _ => panic!("Unexpected device {}", d),
+        }
+    }
+}
+
+#[test]
+fn test_16_conditions() {
+    let devices = test_generation("tests/16-conditions").unwrap();
+    let mut names: Vec<_> = devices.iter().map(|d| d.name.as_str()).collect();
+    names.sort_unstable();
+    assert_eq!(names, ["zram0", "zram2", "zram4", "zram6"]);
+
+    for d in &devices {
+        match d.name.as_str() {
+            "zram0" => assert_eq!(
+                d.condition_memory,
+                Some((config::Comparison::GreaterEqual, 512))
+            ),
+            "zram2" => assert_eq!(d.condition_kernel_command_line.len(), 2),
+            "zram4" => assert_eq!(
+                d.condition_path_exists,
+                Some(config::Negatable {
+                    negate: false,
+                    value: PathBuf::from("/etc/zram-enable")
+                })
+            ),
+            "zram6" => assert_eq!(
+                d.condition_architecture.as_ref().map(|c| c.negate),
+                Some(true)
+            ),
+            _ => panic!("Unexpected device {}", d),
+        }
+    }
+}
+
+#[test]
+fn test_17_verify() {
+    let rootdir = prepare_directory(Path::new("tests/17-verify")).unwrap();
+    let root = rootdir.path();
+
+    let problems: Vec<_> = config::verify_config(root, &[])
+        .unwrap()
+        .iter()
+        .map(|p| {
+            (
+                p.path.strip_prefix(root).unwrap().display().to_string(),
+                p.line,
+                p.severity,
+                format!("{:#}", p.cause),
+            )
+        })
+        .collect();
+
+    let conf = "etc/systemd/zram-generator.conf";
+    let dropin = "etc/systemd/zram-generator.conf.d/10-broken.conf";
+    let expected: Vec<(&str, Option<usize>, config::Severity, &str)> = vec![
+        (
+            conf,
+            Some(8),
+            config::Severity::Skipped,
+            "zram1: zram-size=-4096 < 0 (ram = 8192)",
+        ),
+        (
+            conf,
+            Some(9),
+            config::Severity::Fatal,
+            "zram1: mount point /var/tmp also used by zram0",
+        ),
+        (
+            conf,
+            Some(10),
+            config::Severity::Fatal,
+            "zram1: writeback device /dev/sdb1 also used by zram0",
+        ),
+        (
+            conf,
+            Some(11),
+            config::Severity::Ignored,
+            "unknown key compresion-algorithm",
+        ),
+        (
+            conf,
+            Some(14),
+            config::Severity::Advisory,
+            "node_ram is not available on this machine",
+        ),
+        (
+            conf,
+            Some(15),
+            config::Severity::Advisory,
+            "compression algorithm lzo not found in /proc/crypto",
+        ),
+        (
+            conf,
+            Some(18),
+            config::Severity::Ignored,
+            "unknown section \"swap\"",
+        ),
+        (
+            dropin,
+            Some(1),
+            config::Severity::Ignored,
+            "settings outside of section: host-memory-limit",
+        ),
+        (
+            dropin,
+            Some(4),
+            config::Severity::Skipped,
+            "zram4 zram-size: EofWhileParsing(\"value\")",
+        ),
+        (
+            dropin,
+            Some(7),
+            config::Severity::Advisory,
+            "deprecated: SI suffix in expression \"min(ram / 2, 4G)\" \
+             multiplies by a power of 10, write sizes in MiB instead",
+        ),
+    ];
+    assert_eq!(
+        problems,
+        expected
+            .into_iter()
+            .map(|(p, l, s, m)| (p.to_string(), l, s, m.to_string()))
+            .collect::<Vec<_>>()
+    );
+
+    let problems = config::verify_config(root, &[root.join(dropin)]).unwrap();
+    assert_eq!(problems.len(), 3);
+}
+
+#[test]
+fn test_18_memory_hotplug() {
+    let devices = test_generation("tests/18-memory-hotplug").unwrap();
+    assert_eq!(devices.len(), 4);
+
+    for d in &devices {
+        let expected = match d.name.as_str() {
+            "zram0" | "zram2" => config::HotplugPolicy::GrowOnly,
+            "zram1" => config::HotplugPolicy::GrowAndShrink,
+            "zram3" => config::HotplugPolicy::Ignore,
+            _ => panic!("Unexpected device {}", d),
+        };
+        assert_eq!(d.memory_hotplug, expected);
+    }
+}
+
+#[test]
+fn test_19_sysctl() {
+    let devices = test_generation("tests/19-sysctl").unwrap();
+    assert_eq!(devices.len(), 1);
+
+    let rootdir = prepare_directory(Path::new("tests/19-sysctl")).unwrap();
+    let config =
+        config::read_config(rootdir.path(), false, config::Virtualization::fake("none")).unwrap();
+    let sysctl: Vec<_> = config
+        .sysctl
+        .iter()
+        .map(|s| (&s.name[..], &s.value[..], s.origin.as_ref().unwrap().line))
+        .collect();
+    assert_eq!(
+        sysctl,
+        [
+            ("vm.page-cluster", "0", 5),
+            ("vm.swappiness", "180", 4),
+            ("vm.watermark_scale_factor", "125", 6),
+        ]
+    );
+}
+
+#[test]
+fn test_20_defaults() {
+    let devices = test_generation("tests/20-defaults").unwrap();
+    assert_eq!(devices.len(), 2);
+
+    for d in &devices {
+        let algorithms: Vec<_> = d
+            .compression_algorithms
+            .iter()
+            .map(|a| &a.name[..])
+            .collect();
+        assert_eq!(algorithms, ["zstd", "lz4"]);
+        assert_eq!(d.zram_size.as_ref().map(z_s_name), Some("ram / 4"));
+        match d.name.as_str() {
+            "zram0" => {
+                assert!(d.is_swap());
+                assert_eq!(d.swap_priority, 75);
+            }
+            "zram1" => {
+                assert!(!d.is_swap());
+                assert_eq!(d.swap_priority, 10);
+            }
+            _ => panic!("Unexpected device {}", d),
+        }
+    }
+}
+
+#[test]
+fn test_21_disabled() {
+    let devices = test_generation("tests/21-disabled").unwrap();
+    assert!(devices.is_empty());
+}
+
+#[test]
+fn test_22_broken_section() {
+    let devices = test_generation("tests/22-broken-section").unwrap();
+    assert_eq!(devices.len(), 2);
+
+    // zram1 is skipped entirely, the broken [zram2] override as if it weren't there
+    let d = devices.iter().find(|d| d.name == "zram0").unwrap();
+    assert_eq!(d.swap_priority, 50);
+    let d = devices.iter().find(|d| d.name == "zram2").unwrap();
+    assert_eq!(d.disksize, 1024 * 1024 * 1024);
+    assert!(d.compression_algorithms.is_empty());
+    assert_eq!(d.swap_priority, 50);
+}
+
+#[test]
+fn test_23_host_memory_minimum() {
+    let devices = test_generation("tests/23-host-memory-minimum").unwrap();
+    assert_eq!(devices.len(), 1);
+    let d = &devices[0];
+    assert_eq!(d.name, "zram0");
+    assert_eq!(d.host_memory_minimum_mb, Some(4096));
+}
+
+#[test]
+fn test_24_mount_attributes() {
+    let devices = test_generation("tests/24-mount-attributes").unwrap();
+    assert_eq!(devices.len(), 2);
+
+    for d in &devices {
+        assert!(!d.is_swap());
+        match &d.name[..] {
+            "zram0" => {
+                assert_eq!(d.root_mode, Some(0o1777));
+                assert_eq!(d.root_owner, None);
+                assert_eq!(
+                    d.mount_options(),
+                    "discard,nosuid,nodev,context=\"system_u:object_r:tmp_t:s0\""
+                );
+            }
+            "zram1" => {
+                assert_eq!(d.root_mode, Some(0o750));
+                assert_eq!(d.root_owner.as_deref(), Some("builder:builder"));
+                assert_eq!(d.selinux_context, None);
+                assert_eq!(d.mount_options(), "discard");
+            }
+            _ => panic!("Unexpected device {}", d),
+        }
+    }
+}
+
+#[test]
+fn test_25_mount_dependencies() {
+    let devices = test_generation("tests/25-mount-dependencies").unwrap();
+    assert_eq!(devices.len(), 3);
+
+    for d in &devices {
+        match &d.name[..] {
+            "zram0" => {
+                assert_eq!(d.before, ["builder.service"]);
+                assert_eq!(
+                    d.wanted_by.as_deref(),
+                    Some(&["builder.service".to_string()][..])
+                );
+            }
+            "zram1" => {
+                assert!(d.before.is_empty());
+                assert_eq!(d.wanted_by, None);
+                assert_eq!(
+                    d.x_systemd_options().collect::<Vec<_>>(),
+                    [
+                        ("wanted-by", "initrd-fs.target"),
+                        ("after", "sysroot.mount"),
+                        ("mount-timeout", "30s")
+                    ]
+                );
+            }
+            "zram2" => {
+                assert_eq!(d.x_systemd_options().count(), 2);
+            }
+            _ => panic!("Unexpected device {}", d),
+        }
+    }
+}
+
+#[test]
+fn test_26_dump_config() {
+    let srcroot = Path::new("tests/26-dump-config");
+    let rootdir = prepare_directory(srcroot).unwrap();
+    let root = rootdir.path();
+
+    let kernel_override = config::kernel_zram_option(root) == Some(true);
+    let mut out = vec![];
+    config::dump_config(
+        root,
+        kernel_override,
+        config::Virtualization::fake("none"),
+        &mut out,
+    )
+    .unwrap();
+
+    // Origins are absolute paths
+    let out = String::from_utf8(out)
+        .unwrap()
+        .replace(&format!("{}/", root.display()), "");
+    let expected = fs::read_to_string(srcroot.join("dump.expected")).unwrap();
+    assert_eq!(out, expected);
+}
+
+#[test]
+fn test_27_layering() {
+    let devices = test_generation("tests/27-layering").unwrap();
+    assert_eq!(devices.len(), 2);
+
+    // The admin's drop-in overrides the vendor's, even with less specific sections,
+    // while [zram0-1] beats [zram*] within it, and the inverted [zram3-1] is skipped
+    for d in &devices {
+        assert!(d.name == "zram0" || d.name == "zram1", "{}", d);
+        assert_eq!(
+            d.compression_algorithms.first().map(|alg| &alg.name[..]),
+            Some("zstd")
+        );
+        assert_eq!(d.swap_priority, 20);
+        assert!(d.origins["swap-priority"]
+            .path
+            .ends_with("etc/systemd/zram-generator.conf.d/90-admin.conf"));
+    }
+}
+
+#[test]
+fn test_28_virtualization() {
+    let devices = test_generation("tests/28-virtualization").unwrap();
+    let mut names: Vec<_> = devices.iter().map(|d| d.name.as_str()).collect();
+    names.sort_unstable();
+    assert_eq!(names, ["zram0", "zram2", "zram3", "zram5"]);
+}